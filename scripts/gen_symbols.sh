@@ -0,0 +1,38 @@
+#!/bin/bash -e
+set -o pipefail
+# リンカが.efiの隣に作るPDBから、関数のシンボル表を作って標準出力に書く
+# 1行に1つ「RVA 大きさ 関数名」(RVAと大きさは16進)で、RVAの小さい順に並べる
+# wasabiは起動時にESPからこれを読み、バックトレースをwasabi::draw_line+0x3aのように表示する
+# 使い方: gen_symbols.sh path/to/wasabi.efi > mnt/EFI/BOOT/WASABI.SYM
+
+PATH_TO_EFI="$1"
+PATH_TO_PDB="${PATH_TO_EFI%.efi}.pdb"
+
+# PDBのアドレスは「セクション番号(1から):セクション内のオフセット」なので、
+# 各セクションのRVA(VMA - ImageBase)を10進で並べておく
+IMAGE_BASE=$(llvm-objdump -p "${PATH_TO_EFI}" | awk '$1 == "ImageBase" { print $2 }')
+SECTION_RVAS=""
+for VMA in $(llvm-objdump -h "${PATH_TO_EFI}" | awk '$1 ~ /^[0-9]+$/ { print $4 }'); do
+  SECTION_RVAS="${SECTION_RVAS} $((16#${VMA} - 16#${IMAGE_BASE}))"
+done
+
+# S_GPROC32/S_LPROC32の行に関数名が、次の行に「addr = 0001:10880, code size = 57」がある
+llvm-pdbutil dump --symbols "${PATH_TO_PDB}" | awk -v section_rvas="${SECTION_RVAS}" '
+  BEGIN { split(section_rvas, rvas, " ") }
+  /S_[GL]PROC32/ {
+    name = $0
+    sub(/^[^`]*`/, "", name)
+    sub(/`$/, "", name)
+    next
+  }
+  name != "" && /addr = [0-9]+:[0-9]+, code size = [0-9]+/ {
+    match($0, /addr = [0-9]+:[0-9]+/)
+    split(substr($0, RSTART + 7, RLENGTH - 7), addr, ":")
+    match($0, /code size = [0-9]+/)
+    size = substr($0, RSTART + 12, RLENGTH - 12)
+    if (size > 0 && (addr[1] + 0) in rvas) {
+      printf "%08x %x %s\n", rvas[addr[1] + 0] + addr[2], size, name
+    }
+    name = ""
+  }
+' | LC_ALL=C sort
@@ -6,9 +6,36 @@ PATH_TO_EFI="$1"
 rm -rf mnt
 mkdir -p mnt/EFI/BOOT/
 cp ${PATH_TO_EFI} mnt/EFI/BOOT/BOOTX64.EFI
+# バックトレースに関数名を出すためのシンボル表。作れなくても起動はできる
+if ! scripts/gen_symbols.sh "${PATH_TO_EFI}" > mnt/EFI/BOOT/WASABI.SYM; then
+  echo "wasabi: failed to generate symbols, backtraces will not be symbolized" >&2
+  rm -f mnt/EFI/BOOT/WASABI.SYM
+fi
+
+# シリアル(COM1)はいつも端末につなぐ
+# cargo testで作られたバイナリ(target/.../deps/以下)は画面を出さず、結果はシリアルだけで見る
+QEMU_DISPLAY_ARGS=()
+if [[ "${PATH_TO_EFI}" == */deps/* ]]; then
+  QEMU_DISPLAY_ARGS=(-display none)
+fi
+
+set +e
 qemu-system-x86_64 \
   -m 4G \
   -bios third_party/ovmf/RELEASEX64_OVMF.fd \
   -drive format=raw,file=fat:rw:mnt \
-  -device isa-debug-exit,iobase=0xf4,iosize=0x01
+  -device isa-debug-exit,iobase=0xf4,iosize=0x01 \
+  -serial stdio \
+  "${QEMU_DISPLAY_ARGS[@]}"
+RETCODE=$?
+set -e
 
+# isa-debug-exitに書き込んだ値vは、終了コード(v << 1) | 1になる
+# QemuExitCode::Success(0x10)は33, QemuExitCode::Failed(0x11)は35
+if [ ${RETCODE} -eq 33 ]; then
+  exit 0
+elif [ ${RETCODE} -eq 35 ]; then
+  echo "wasabi: test failed" >&2
+  exit 1
+fi
+exit ${RETCODE}
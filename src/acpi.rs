@@ -0,0 +1,659 @@
+// ACPIのテーブル
+// RSDPからRSDT/XSDTをたどって、カーネルが使うテーブル(MADT, FADT, HPET, MCFG)を読む
+// AML(DSDTの中身)はここでは解釈しない
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::offset_of;
+use core::mem::size_of;
+use core::slice;
+
+use crate::config_table::checksum_ok;
+use crate::config_table::AcpiRsdp;
+
+// すべてのテーブル(SDT)の先頭にある共通のヘッダ
+#[repr(C)]
+pub struct AcpiSdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const _: () = assert!(offset_of!(AcpiSdtHeader, length) == 4);
+const _: () = assert!(offset_of!(AcpiSdtHeader, oem_id) == 10);
+const _: () = assert!(offset_of!(AcpiSdtHeader, oem_table_id) == 16);
+const _: () = assert!(offset_of!(AcpiSdtHeader, creator_revision) == 32);
+const _: () = assert!(size_of::<AcpiSdtHeader>() == 36);
+
+impl AcpiSdtHeader {
+    // 物理アドレスにあるテーブルのヘッダ(wasabiは物理アドレスをそのまま使う)
+    unsafe fn from_address(address: u64) -> Option<&'static AcpiSdtHeader> {
+        (address as *const AcpiSdtHeader).as_ref()
+    }
+
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    // ヘッダを含むテーブル全体
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) }
+    }
+
+    // ヘッダより後ろの部分
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[size_of::<AcpiSdtHeader>()..]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.length as usize >= size_of::<AcpiSdtHeader>() && checksum_ok(self.bytes())
+    }
+}
+
+impl fmt::Debug for AcpiSdtHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let oem_id = core::str::from_utf8(&self.oem_id).unwrap_or("?");
+        let oem_table_id = core::str::from_utf8(&self.oem_table_id).unwrap_or("?");
+        write!(
+            f,
+            "{} at {:p} rev {} {} bytes OEM {:?} {:?}",
+            self.signature(),
+            self,
+            self.revision,
+            self.length,
+            oem_id,
+            oem_table_id
+        )?;
+        if !self.is_valid() {
+            f.write_str(" (bad checksum)")?;
+        }
+        Ok(())
+    }
+}
+
+// 先頭がAcpiSdtHeaderで始まる、型付きのテーブル
+// 参照を作るのはテーブルがSelfの大きさ以上あるときだけなので、版によって無いフィールドは構造体に入れない
+pub trait AcpiTable: Sized {
+    const SIGNATURE: &'static [u8; 4];
+
+    fn header(&self) -> &AcpiSdtHeader {
+        unsafe { &*(self as *const Self as *const AcpiSdtHeader) }
+    }
+}
+
+// バイト列から、境界に揃っていないかもしれない値を読む
+fn read_u16(bytes: &[u8], ofs: usize) -> u16 {
+    u16::from_le_bytes([bytes[ofs], bytes[ofs + 1]])
+}
+
+fn read_u32(bytes: &[u8], ofs: usize) -> u32 {
+    u32::from_le_bytes(bytes[ofs..ofs + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], ofs: usize) -> u64 {
+    u64::from_le_bytes(bytes[ofs..ofs + 8].try_into().unwrap())
+}
+
+// RSDT(32ビットのアドレス)かXSDT(64ビットのアドレス)から読んだテーブルの一覧
+#[derive(Clone, Copy)]
+pub struct Acpi {
+    rsdp: AcpiRsdp,
+}
+
+impl Acpi {
+    pub fn new(rsdp: AcpiRsdp) -> Acpi {
+        Acpi { rsdp }
+    }
+
+    pub fn rsdp(&self) -> &AcpiRsdp {
+        &self.rsdp
+    }
+
+    // XSDTがあればXSDTを、なければ(壊れていたときも)RSDTを、エントリの大きさと一緒に返す
+    fn root_table(&self) -> Option<(&'static AcpiSdtHeader, usize)> {
+        let table = |address, signature: &[u8; 4]| {
+            unsafe { AcpiSdtHeader::from_address(address) }
+                .filter(|h| &h.signature == signature && h.is_valid())
+        };
+        self.rsdp
+            .xsdt()
+            .and_then(|address| table(address, b"XSDT"))
+            .map(|xsdt| (xsdt, 8))
+            .or_else(|| table(self.rsdp.rsdt_address as u64, b"RSDT").map(|rsdt| (rsdt, 4)))
+    }
+
+    // ルートのテーブルに並んでいるテーブルをすべて列挙する(チェックサムはまだ確かめない)
+    pub fn tables(&self) -> AcpiTableIterator {
+        let (entries, entry_size) = self
+            .root_table()
+            .map_or((&[][..], 8), |(root, size)| (root.data(), size));
+        AcpiTableIterator { entries, entry_size }
+    }
+
+    // FADTが指しているDSDT(ルートのテーブルには並んでいない)
+    pub fn dsdt(&self) -> Option<&'static AcpiSdtHeader> {
+        let fadt = self.find::<Fadt>()?;
+        unsafe { AcpiSdtHeader::from_address(fadt.dsdt()) }
+            .filter(|h| &h.signature == b"DSDT" && h.is_valid())
+    }
+
+    // シグネチャが一致して、チェックサムと長さも正しい最初のテーブル(壊れたものは飛ばす)
+    pub fn find<T: AcpiTable>(&self) -> Option<&'static T> {
+        self.tables()
+            .find(|h| &h.signature == T::SIGNATURE && h.is_valid() && h.length as usize >= size_of::<T>())
+            .map(|h| unsafe { &*(h as *const AcpiSdtHeader as *const T) })
+    }
+}
+
+pub struct AcpiTableIterator {
+    entries: &'static [u8],
+    entry_size: usize,
+}
+
+impl Iterator for AcpiTableIterator {
+    type Item = &'static AcpiSdtHeader;
+
+    fn next(&mut self) -> Option<&'static AcpiSdtHeader> {
+        while self.entries.len() >= self.entry_size {
+            let address = if self.entry_size == 8 {
+                read_u64(self.entries, 0)
+            } else {
+                read_u32(self.entries, 0) as u64
+            };
+            self.entries = &self.entries[self.entry_size..];
+            if let Some(header) = unsafe { AcpiSdtHeader::from_address(address) } {
+                return Some(header);
+            }
+        }
+        None
+    }
+}
+
+// Generic Address Structure
+// レジスタがI/Oポートにあるのか、メモリにあるのかなどを表す
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct AcpiGenericAddress {
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+const _: () = assert!(size_of::<AcpiGenericAddress>() == 12);
+
+impl AcpiGenericAddress {
+    pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+    pub const SPACE_SYSTEM_IO: u8 = 1;
+    pub const SPACE_PCI_CONFIG: u8 = 2;
+
+    const SIZE: usize = size_of::<AcpiGenericAddress>();
+
+    // テーブルの中のバイト列から読む
+    fn from_bytes(bytes: &[u8]) -> AcpiGenericAddress {
+        AcpiGenericAddress {
+            address_space_id: bytes[0],
+            register_bit_width: bytes[1],
+            register_bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+
+    // 古い版のFADTにある、I/Oポートの番号だけのレジスタ
+    fn io_port(port: u32, bit_width: u8) -> AcpiGenericAddress {
+        AcpiGenericAddress {
+            address_space_id: Self::SPACE_SYSTEM_IO,
+            register_bit_width: bit_width,
+            register_bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        }
+    }
+}
+
+impl fmt::Debug for AcpiGenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let address = self.address;
+        match self.address_space_id {
+            Self::SPACE_SYSTEM_MEMORY => write!(f, "MMIO {address:#x}")?,
+            Self::SPACE_SYSTEM_IO => write!(f, "I/O {address:#x}")?,
+            Self::SPACE_PCI_CONFIG => write!(f, "PCI {address:#x}")?,
+            id => write!(f, "space {id} {address:#x}")?,
+        }
+        write!(f, " ({} bits)", self.register_bit_width)
+    }
+}
+
+// MADT(Multiple APIC Description Table)
+#[repr(C)]
+pub struct Madt {
+    header: AcpiSdtHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+const _: () = assert!(offset_of!(Madt, local_apic_address) == 36);
+const _: () = assert!(offset_of!(Madt, flags) == 40);
+
+impl AcpiTable for Madt {
+    const SIGNATURE: &'static [u8; 4] = b"APIC";
+}
+
+// MADTの後ろに並んでいるエントリ(よく使うものだけ)
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        io_apic_id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    // ISAのIRQ(source)が、別のGSIにつながっている
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicNmi {
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    Unknown {
+        entry_type: u8,
+        length: u8,
+    },
+}
+
+impl MadtEntry {
+    // entryは種類と長さの2バイトを含む
+    fn parse(entry: &[u8]) -> MadtEntry {
+        let (entry_type, length) = (entry[0], entry[1]);
+        match (entry_type, length) {
+            (0, 8..) => MadtEntry::LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                flags: read_u32(entry, 4),
+            },
+            (1, 12..) => MadtEntry::IoApic {
+                io_apic_id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            },
+            (2, 10..) => MadtEntry::InterruptSourceOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            },
+            (4, 6..) => MadtEntry::LocalApicNmi {
+                processor_id: entry[2],
+                flags: read_u16(entry, 3),
+                lint: entry[5],
+            },
+            (5, 12..) => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(entry, 4),
+            },
+            (9, 16..) => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(entry, 4),
+                flags: read_u32(entry, 8),
+                processor_uid: read_u32(entry, 12),
+            },
+            _ => MadtEntry::Unknown { entry_type, length },
+        }
+    }
+}
+
+impl Madt {
+    pub fn entries(&self) -> MadtEntryIterator {
+        MadtEntryIterator {
+            entries: &self.header.bytes()[size_of::<Madt>()..],
+            _table: PhantomData,
+        }
+    }
+
+    // Local APICのアドレス(64ビットで上書きされていればそちら)
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|e| match e {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+}
+
+pub struct MadtEntryIterator<'a> {
+    entries: &'a [u8],
+    _table: PhantomData<&'a Madt>,
+}
+
+impl Iterator for MadtEntryIterator<'_> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.entries.len() < 2 {
+            return None;
+        }
+        let length = self.entries[1] as usize;
+        // 長さが壊れているときは、それ以上読まない
+        if length < 2 || length > self.entries.len() {
+            self.entries = &[];
+            return None;
+        }
+        let (entry, rest) = self.entries.split_at(length);
+        self.entries = rest;
+        Some(MadtEntry::parse(entry))
+    }
+}
+
+// FADT(Fixed ACPI Description Table)。シグネチャは"FACP"
+// 版によって長さが違うので、構造体はACPI 1.0にもあるflagsまでにして、
+// それより後ろのフィールドはlengthを確かめてからheader.bytes()から読む
+#[repr(C, packed)]
+pub struct Fadt {
+    header: AcpiSdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    _reserved0: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_int: u16,
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    _reserved1: [u8; 2], // S4BIOS_REQ, PSTATE_CNT
+    pub pm1a_evt_blk: u32,
+    pub pm1b_evt_blk: u32,
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    _reserved2: u32, // PM2_CNT_BLK
+    pub pm_tmr_blk: u32,
+    _reserved3: [u32; 2], // GPE0_BLK, GPE1_BLK
+    pub pm1_evt_len: u8,
+    pub pm1_cnt_len: u8,
+    _reserved4: [u8; 19], // PM2_CNT_LEN .. CENTURY
+    pub iapc_boot_arch: u16,
+    _reserved5: u8,
+    pub flags: u32,
+}
+
+const _: () = assert!(offset_of!(Fadt, dsdt) == 40);
+const _: () = assert!(offset_of!(Fadt, sci_int) == 46);
+const _: () = assert!(offset_of!(Fadt, pm1a_evt_blk) == 56);
+const _: () = assert!(offset_of!(Fadt, pm1a_cnt_blk) == 64);
+const _: () = assert!(offset_of!(Fadt, pm_tmr_blk) == 76);
+const _: () = assert!(offset_of!(Fadt, pm1_evt_len) == 88);
+const _: () = assert!(offset_of!(Fadt, iapc_boot_arch) == 109);
+const _: () = assert!(offset_of!(Fadt, flags) == 112);
+const _: () = assert!(size_of::<Fadt>() == 116);
+
+impl AcpiTable for Fadt {
+    const SIGNATURE: &'static [u8; 4] = b"FACP";
+}
+
+impl Fadt {
+    // flagsのビット: PMタイマーが32ビット(立っていなければ24ビット)
+    pub const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+    // flagsのビット: reset_regが使える
+    pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+    // ACPI 2.0以降で増えたフィールドの、テーブルの先頭からのオフセット
+    const RESET_REG: usize = 116;
+    const RESET_VALUE: usize = 128;
+    const X_DSDT: usize = 140;
+    const X_PM1A_CNT_BLK: usize = 172;
+    const X_PM1B_CNT_BLK: usize = 184;
+
+    // ofsからlenバイトのフィールド(テーブルがそこまで無ければNone)
+    fn field(&self, ofs: usize, len: usize) -> Option<&[u8]> {
+        self.header().bytes().get(ofs..ofs + len)
+    }
+
+    // DSDT(AMLで書かれた、デバイスの説明)の物理アドレス
+    pub fn dsdt(&self) -> u64 {
+        match self.field(Self::X_DSDT, 8).map(|b| read_u64(b, 0)) {
+            Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+            _ => self.dsdt as u64,
+        }
+    }
+
+    fn pm1_cnt_blk(&self, x_ofs: usize, blk: u32) -> Option<AcpiGenericAddress> {
+        match self.field(x_ofs, AcpiGenericAddress::SIZE).map(AcpiGenericAddress::from_bytes) {
+            Some(x_blk) if { x_blk.address } != 0 => Some(x_blk),
+            _ if blk != 0 => Some(AcpiGenericAddress::io_port(blk, self.pm1_cnt_len * 8)),
+            _ => None,
+        }
+    }
+
+    // PM1aコントロールレジスタ(SLP_TYPとSLP_ENを書き込むとスリープや電源断ができる)
+    pub fn pm1a_cnt_blk(&self) -> Option<AcpiGenericAddress> {
+        self.pm1_cnt_blk(Self::X_PM1A_CNT_BLK, self.pm1a_cnt_blk)
+    }
+
+    pub fn pm1b_cnt_blk(&self) -> Option<AcpiGenericAddress> {
+        self.pm1_cnt_blk(Self::X_PM1B_CNT_BLK, self.pm1b_cnt_blk)
+    }
+
+    // ACPI PMタイマー(3.579545MHzで増え続けるカウンタ)のI/Oポートと、カウンタのビット数
+    pub fn pm_timer(&self) -> Option<(u16, u32)> {
+        let bits = if { self.flags } & Self::FLAG_TMR_VAL_EXT != 0 { 32 } else { 24 };
+        (self.pm_tmr_blk != 0).then_some((self.pm_tmr_blk as u16, bits))
+    }
+
+    // リセットレジスタと、そこに書き込む値
+    pub fn reset_register(&self) -> Option<(AcpiGenericAddress, u8)> {
+        if { self.flags } & Self::FLAG_RESET_REG_SUP == 0 {
+            return None;
+        }
+        let reg = self.field(Self::RESET_REG, AcpiGenericAddress::SIZE)?;
+        let value = self.field(Self::RESET_VALUE, 1)?;
+        Some((AcpiGenericAddress::from_bytes(reg), value[0]))
+    }
+}
+
+// HPET(High Precision Event Timer)
+#[repr(C, packed)]
+pub struct Hpet {
+    header: AcpiSdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: AcpiGenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+const _: () = assert!(offset_of!(Hpet, base_address) == 40);
+const _: () = assert!(offset_of!(Hpet, hpet_number) == 52);
+const _: () = assert!(size_of::<Hpet>() == 56);
+
+impl AcpiTable for Hpet {
+    const SIGNATURE: &'static [u8; 4] = b"HPET";
+}
+
+impl Hpet {
+    // レジスタのあるメモリのアドレス
+    pub fn base_address(&self) -> u64 {
+        self.base_address.address
+    }
+}
+
+// MCFG(PCI Expressのコンフィギュレーション空間をメモリに割り当てた範囲, ECAM)
+#[repr(C)]
+pub struct Mcfg {
+    header: AcpiSdtHeader,
+    _reserved: [u8; 8],
+}
+
+const _: () = assert!(size_of::<Mcfg>() == 44);
+
+impl AcpiTable for Mcfg {
+    const SIGNATURE: &'static [u8; 4] = b"MCFG";
+}
+
+// PCIのセグメントごとのECAMの範囲
+#[derive(Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl McfgEntry {
+    const SIZE: usize = 16;
+
+    // バス1つあたり、デバイス32個 * ファンクション8個 * 4KiB
+    pub fn size(&self) -> u64 {
+        (self.end_bus as u64 - self.start_bus as u64 + 1) << 20
+    }
+}
+
+impl fmt::Debug for McfgEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "segment {} bus {:02x}-{:02x} ECAM {:#x}-{:#x}",
+            self.segment,
+            self.start_bus,
+            self.end_bus,
+            self.base_address,
+            self.base_address + self.size()
+        )
+    }
+}
+
+impl Mcfg {
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + '_ {
+        self.header.bytes()[size_of::<Mcfg>()..]
+            .chunks_exact(McfgEntry::SIZE)
+            .map(|e| McfgEntry {
+                base_address: read_u64(e, 0),
+                segment: read_u16(e, 8),
+                start_bus: e[10],
+                end_bus: e[11],
+            })
+    }
+}
+
+// acpiコマンド: 見つかったテーブルと、その中身で大事なところを表示する
+pub fn dump(w: &mut impl fmt::Write, acpi: &Acpi) -> fmt::Result {
+    writeln!(w, "{:?}", acpi.rsdp())?;
+    for table in acpi.tables() {
+        writeln!(w, "  {table:?}")?;
+    }
+    if let Some(madt) = acpi.find::<Madt>() {
+        writeln!(w, "MADT: Local APIC {:#x}", madt.local_apic_address())?;
+        for entry in madt.entries() {
+            writeln!(w, "  {entry:?}")?;
+        }
+    }
+    if let Some(fadt) = acpi.find::<Fadt>() {
+        writeln!(w, "FADT: DSDT {:#x} SCI {}", fadt.dsdt(), { fadt.sci_int })?;
+        if let Some(pm1a) = fadt.pm1a_cnt_blk() {
+            writeln!(w, "  PM1a_CNT {pm1a:?}")?;
+        }
+        if let Some(pm1b) = fadt.pm1b_cnt_blk() {
+            writeln!(w, "  PM1b_CNT {pm1b:?}")?;
+        }
+        if let Some((reg, value)) = fadt.reset_register() {
+            writeln!(w, "  RESET_REG {reg:?} value {value:#x}")?;
+        }
+    }
+    if let Some(hpet) = acpi.find::<Hpet>() {
+        writeln!(w, "HPET: base {:#x} min tick {}", hpet.base_address(), { hpet.minimum_tick })?;
+    }
+    if let Some(mcfg) = acpi.find::<Mcfg>() {
+        writeln!(w, "MCFG:")?;
+        for entry in mcfg.entries() {
+            writeln!(w, "  {entry:?}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    // ヘッダとdataからなる、チェックサムの合ったテーブル(u64で確保して境界を揃える)
+    fn table_bytes(signature: &[u8; 4], data: &[u8]) -> &'static mut [u8] {
+        let length = size_of::<AcpiSdtHeader>() + data.len();
+        let words: &'static mut [u64] = vec![0; length.div_ceil(8)].leak();
+        let bytes = unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, length) };
+        bytes[..4].copy_from_slice(signature);
+        bytes[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+        bytes[size_of::<AcpiSdtHeader>()..].copy_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes[offset_of!(AcpiSdtHeader, checksum)] = 0u8.wrapping_sub(sum);
+        bytes
+    }
+
+    fn table(bytes: &'static [u8]) -> &'static AcpiSdtHeader {
+        unsafe { &*(bytes.as_ptr() as *const AcpiSdtHeader) }
+    }
+
+    fn address(table: &AcpiSdtHeader) -> u64 {
+        table as *const AcpiSdtHeader as u64
+    }
+
+    #[test_case]
+    fn falls_back_to_rsdt_when_xsdt_is_corrupt() {
+        let hpet = table(table_bytes(b"HPET", &[0; size_of::<Hpet>() - size_of::<AcpiSdtHeader>()]));
+        let rsdt_entry = u32::try_from(address(hpet)).unwrap();
+        let rsdt = table(table_bytes(b"RSDT", &rsdt_entry.to_le_bytes()));
+        let xsdt_bytes = table_bytes(b"XSDT", &address(hpet).to_le_bytes());
+        let mut rsdp = AcpiRsdp::default();
+        rsdp.revision = 2;
+        rsdp.rsdt_address = u32::try_from(address(rsdt)).unwrap();
+        rsdp.xsdt_address = xsdt_bytes.as_ptr() as u64;
+        let acpi = Acpi::new(rsdp);
+        assert_eq!(acpi.root_table().map(|(root, _)| &root.signature), Some(b"XSDT"));
+        assert!(acpi.find::<Hpet>().is_some());
+
+        xsdt_bytes[offset_of!(AcpiSdtHeader, checksum)] ^= 1;
+        assert_eq!(acpi.root_table().map(|(root, _)| &root.signature), Some(b"RSDT"));
+        assert_eq!(acpi.tables().count(), 1);
+        assert!(acpi.find::<Hpet>().is_some());
+    }
+
+    #[test_case]
+    fn reads_acpi_1_fadt_without_extended_fields() {
+        let mut data = [0u8; size_of::<Fadt>() - size_of::<AcpiSdtHeader>()];
+        let ofs = |field| field - size_of::<AcpiSdtHeader>();
+        data[ofs(offset_of!(Fadt, dsdt))..][..4].copy_from_slice(&0x1234u32.to_le_bytes());
+        data[ofs(offset_of!(Fadt, pm1a_cnt_blk))..][..4].copy_from_slice(&0x604u32.to_le_bytes());
+        data[ofs(offset_of!(Fadt, pm1_cnt_len))] = 2;
+        data[ofs(offset_of!(Fadt, flags))..][..4].copy_from_slice(&Fadt::FLAG_RESET_REG_SUP.to_le_bytes());
+        let fadt = unsafe { &*(table(table_bytes(b"FACP", &data)) as *const AcpiSdtHeader as *const Fadt) };
+        assert_eq!(fadt.dsdt(), 0x1234);
+        let pm1a = fadt.pm1a_cnt_blk().unwrap();
+        assert_eq!(pm1a.address_space_id, AcpiGenericAddress::SPACE_SYSTEM_IO);
+        assert_eq!({ pm1a.address }, 0x604);
+        assert_eq!(pm1a.register_bit_width, 16);
+        assert!(fadt.pm1b_cnt_blk().is_none());
+        // RESET_REG_SUPが立っていても、テーブルにreset_regが無ければ使わない
+        assert!(fadt.reset_register().is_none());
+    }
+}
@@ -0,0 +1,89 @@
+// フレームポインタ(RBP)をたどるバックトレース
+// .cargo/config.tomlで-Cforce-frame-pointersを指定しているので、どの関数も
+// [rbp]に呼び出し元のrbp、[rbp + 8]に戻りアドレスを置いている
+
+use core::fmt;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use crate::symbols::lookup;
+use crate::x86::read_rbp;
+
+// これより深くはたどらない(フレームが壊れていて循環しているときのため)
+const MAX_FRAMES: usize = 32;
+// 1つのフレームがこれより大きければ、壊れているとみなす
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+// このプログラムが読み込まれた場所。戻りアドレスをイメージの先頭からのオフセットで表示するために使う
+static IMAGE_BASE: AtomicU64 = AtomicU64::new(0);
+static IMAGE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_image_range(base: u64, size: u64) {
+    IMAGE_BASE.store(base, Ordering::SeqCst);
+    IMAGE_SIZE.store(size, Ordering::SeqCst);
+}
+
+// (先頭のアドレス, 大きさ)
+pub fn image_range() -> (u64, u64) {
+    (IMAGE_BASE.load(Ordering::SeqCst), IMAGE_SIZE.load(Ordering::SeqCst))
+}
+
+// addressがこのプログラムの中なら、イメージの先頭からのオフセットを返す
+pub fn image_offset(address: u64) -> Option<u64> {
+    let base = IMAGE_BASE.load(Ordering::SeqCst);
+    let size = IMAGE_SIZE.load(Ordering::SeqCst);
+    (base != 0 && (base..base + size).contains(&address)).then(|| address - base)
+}
+
+// 呼び出し元をたどっていく。最初に返すのは、frames()を呼んだ関数の戻りアドレス
+pub struct FrameIterator {
+    rbp: u64,
+    depth: usize,
+}
+
+impl Iterator for FrameIterator {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.rbp == 0 || self.rbp % 8 != 0 || self.depth >= MAX_FRAMES {
+            return None;
+        }
+        let frame = self.rbp as *const u64;
+        let (caller_rbp, return_address) = unsafe { (*frame, *frame.add(1)) };
+        // スタックは下に伸びるので、呼び出し元のフレームは必ず上にある
+        self.rbp = if caller_rbp > self.rbp && caller_rbp - self.rbp <= MAX_FRAME_SIZE {
+            caller_rbp
+        } else {
+            0
+        };
+        self.depth += 1;
+        (return_address != 0).then_some(return_address)
+    }
+}
+
+#[inline(always)]
+pub fn frames() -> FrameIterator {
+    FrameIterator {
+        rbp: read_rbp(),
+        depth: 0,
+    }
+}
+
+// 今の呼び出し元の一覧を表示する。シンボル表が読み込まれていれば関数名も出す
+#[inline(always)]
+pub fn write_backtrace(w: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(w, "Backtrace:")?;
+    for (i, address) in frames().enumerate() {
+        write!(w, "  #{i:<2} {address:#018x}")?;
+        // 戻りアドレスはcall命令の次を指していて、関数の最後の呼び出しなら次の関数の先頭になってしまう
+        // そのため1つ前のアドレスで関数を探す
+        if let Some((name, offset)) = lookup(address - 1) {
+            write!(w, " {name}+{:#x}", offset + 1)?;
+        }
+        if let Some(offset) = image_offset(address) {
+            write!(w, " (image+{offset:#x})")?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+// UEFIのSimple Text Input Protocol(ConIn)
+// Boot Servicesが使える間は、キーボードのドライバを書かなくてもキー入力を受け取れる
+
+use core::mem::offset_of;
+
+use crate::EfiBootServiceTable;
+use crate::EfiEvent;
+use crate::EfiResult;
+use crate::EfiStatus;
+
+// 押されたキー
+// 文字のキーはunicode_charに、矢印キーなどの文字でないキーはscan_codeに値が入る
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EfiInputKey {
+    pub scan_code: u16,
+    pub unicode_char: u16,
+}
+
+#[allow(dead_code)]
+impl EfiInputKey {
+    pub const SCAN_NULL: u16 = 0x00;
+    pub const SCAN_UP: u16 = 0x01;
+    pub const SCAN_DOWN: u16 = 0x02;
+    pub const SCAN_RIGHT: u16 = 0x03;
+    pub const SCAN_LEFT: u16 = 0x04;
+    pub const SCAN_HOME: u16 = 0x05;
+    pub const SCAN_END: u16 = 0x06;
+    pub const SCAN_INSERT: u16 = 0x07;
+    pub const SCAN_DELETE: u16 = 0x08;
+    pub const SCAN_PAGE_UP: u16 = 0x09;
+    pub const SCAN_PAGE_DOWN: u16 = 0x0a;
+    pub const SCAN_F1: u16 = 0x0b;
+    pub const SCAN_F10: u16 = 0x14;
+    pub const SCAN_ESC: u16 = 0x17;
+
+    // 文字のキーなら、その文字を返す(Enterは'\r'、BackSpaceは'\x08'になる)
+    pub fn char(&self) -> Option<char> {
+        if self.unicode_char == 0 {
+            None
+        } else {
+            char::from_u32(self.unicode_char as u32)
+        }
+    }
+}
+
+#[repr(C)]
+pub struct EfiSimpleTextInputProtocol {
+    _reserved0: [u64; 1], // Reset
+    read_key_stroke: extern "win64" fn(this: *const Self, key: *mut EfiInputKey) -> EfiStatus,
+    // キーが押されるとシグナル状態になるイベント。WaitForEventで待つのに使う
+    wait_for_key: EfiEvent,
+}
+
+const _: () = assert!(offset_of!(EfiSimpleTextInputProtocol, read_key_stroke) == 8);
+const _: () = assert!(offset_of!(EfiSimpleTextInputProtocol, wait_for_key) == 16);
+
+impl EfiSimpleTextInputProtocol {
+    // 押されたキーがあれば返す。なければすぐにNoneを返す
+    pub fn read_key_stroke(&self) -> EfiResult<Option<EfiInputKey>> {
+        let mut key = EfiInputKey {
+            scan_code: 0,
+            unicode_char: 0,
+        };
+        match (self.read_key_stroke)(self, &mut key).into_result() {
+            Ok(()) => Ok(Some(key)),
+            Err(EfiStatus::NOT_READY) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // キーが押されるまで待って、そのキーを返す
+    pub fn wait_key(&self, boot_services: &EfiBootServiceTable) -> EfiResult<EfiInputKey> {
+        loop {
+            boot_services.wait_for_event(&[self.wait_for_key])?;
+            // イベントが来ても、別の誰かが先にキーを読んでいることがあるので確認する
+            if let Some(key) = self.read_key_stroke()? {
+                return Ok(key);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// UEFIのSimple Text Output Protocol(ConOut)
+// GOPが見つからなくても、Boot Servicesが使える間はファームウェアのコンソールに文字を出せる
+
+use core::fmt;
+use core::mem::offset_of;
+use core::ops::Deref;
+
+use crate::EfiResult;
+use crate::EfiStatus;
+
+// SetAttributeに渡す文字の色と背景色
+// 前景色は16色すべて、背景色は下位8色(BLACKからLIGHTGRAYまで)だけ使える
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct EfiTextAttribute(pub usize);
+
+#[allow(dead_code)]
+impl EfiTextAttribute {
+    pub const BLACK: usize = 0x00;
+    pub const BLUE: usize = 0x01;
+    pub const GREEN: usize = 0x02;
+    pub const CYAN: usize = 0x03;
+    pub const RED: usize = 0x04;
+    pub const MAGENTA: usize = 0x05;
+    pub const BROWN: usize = 0x06;
+    pub const LIGHTGRAY: usize = 0x07;
+    pub const DARKGRAY: usize = 0x08;
+    pub const LIGHTBLUE: usize = 0x09;
+    pub const LIGHTGREEN: usize = 0x0a;
+    pub const LIGHTCYAN: usize = 0x0b;
+    pub const LIGHTRED: usize = 0x0c;
+    pub const LIGHTMAGENTA: usize = 0x0d;
+    pub const YELLOW: usize = 0x0e;
+    pub const WHITE: usize = 0x0f;
+
+    // EFI_TEXT_ATTRマクロと同じ
+    pub const fn new(foreground: usize, background: usize) -> Self {
+        EfiTextAttribute(foreground | ((background & 0x07) << 4))
+    }
+}
+
+#[repr(C)]
+pub struct EfiSimpleTextOutputProtocol {
+    _reserved0: [u64; 1], // Reset
+    output_string: extern "win64" fn(this: *const Self, string: *const u16) -> EfiStatus,
+    _reserved1: [u64; 3], // TestString, QueryMode, SetMode
+    set_attribute: extern "win64" fn(this: *const Self, attribute: usize) -> EfiStatus,
+    clear_screen: extern "win64" fn(this: *const Self) -> EfiStatus,
+    set_cursor_position:
+        extern "win64" fn(this: *const Self, column: usize, row: usize) -> EfiStatus,
+    _reserved2: [u64; 2], // EnableCursor, Mode
+}
+
+const _: () = assert!(offset_of!(EfiSimpleTextOutputProtocol, output_string) == 8);
+const _: () = assert!(offset_of!(EfiSimpleTextOutputProtocol, set_attribute) == 40);
+const _: () = assert!(offset_of!(EfiSimpleTextOutputProtocol, clear_screen) == 48);
+const _: () = assert!(offset_of!(EfiSimpleTextOutputProtocol, set_cursor_position) == 56);
+
+impl EfiSimpleTextOutputProtocol {
+    // stringはUCS-2でNUL終端されている必要がある
+    pub fn output_string(&self, string: &[u16]) -> EfiResult<()> {
+        if string.last() != Some(&0) {
+            return Err(EfiStatus::INVALID_PARAMETER);
+        }
+        (self.output_string)(self, string.as_ptr()).into_result()
+    }
+
+    pub fn set_attribute(&self, attribute: EfiTextAttribute) -> EfiResult<()> {
+        (self.set_attribute)(self, attribute.0).into_result()
+    }
+
+    pub fn clear_screen(&self) -> EfiResult<()> {
+        (self.clear_screen)(self).into_result()
+    }
+
+    #[allow(dead_code)]
+    pub fn set_cursor_position(&self, column: usize, row: usize) -> EfiResult<()> {
+        (self.set_cursor_position)(self, column, row).into_result()
+    }
+}
+
+// 一度にOutputStringに渡す文字数(NUL終端の分を含む)
+const CON_OUT_BUFFER_LEN: usize = 128;
+
+// ConOutにfmt::Writeで書き込むためのラッパー
+// ExitBootServicesの後は使えないので注意
+pub struct ConOutTextWriter<'a> {
+    con_out: &'a EfiSimpleTextOutputProtocol,
+}
+
+impl<'a> ConOutTextWriter<'a> {
+    pub fn new(con_out: &'a EfiSimpleTextOutputProtocol) -> Self {
+        Self { con_out }
+    }
+}
+
+// clear_screenなどのプロトコルの関数をそのまま呼べるようにする
+impl Deref for ConOutTextWriter<'_> {
+    type Target = EfiSimpleTextOutputProtocol;
+
+    fn deref(&self) -> &EfiSimpleTextOutputProtocol {
+        self.con_out
+    }
+}
+
+impl fmt::Write for ConOutTextWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // UTF-8の文字列をUCS-2に変換しながら、バッファがいっぱいになるたびに出力する
+        let mut buf = [0u16; CON_OUT_BUFFER_LEN];
+        let mut len = 0;
+        for c in s.chars() {
+            // ConOutは改行に\r\nが必要
+            if c == '\n' {
+                buf[len] = '\r' as u16;
+                len += 1;
+            }
+            // UCS-2で表せない文字は置き換える
+            let mut units = [0u16; 2];
+            buf[len] = match c.encode_utf16(&mut units) {
+                [u] => *u,
+                _ => '?' as u16,
+            };
+            len += 1;
+            // 次の文字(\r\nで最大2つ)とNULが入る余地を残しておく
+            if len + 3 > buf.len() {
+                buf[len] = 0;
+                self.con_out.output_string(&buf[..=len]).or(Err(fmt::Error))?;
+                len = 0;
+            }
+        }
+        buf[len] = 0;
+        self.con_out.output_string(&buf[..=len]).or(Err(fmt::Error))
+    }
+}
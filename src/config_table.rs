@@ -0,0 +1,360 @@
+// EFI System TableのConfiguration Table
+// ファームウェアが用意したテーブル(ACPI, SMBIOS, Device Treeなど)の場所が、GUIDとポインタの組で並んでいる
+// テーブル自体はACPI_RECLAIMやRUNTIME_SERVICES_DATAなどに置かれるので、ExitBootServicesの後も読める
+
+use core::fmt;
+use core::mem::offset_of;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+
+use crate::EfiGuid;
+use crate::EfiMemoryDescriptor;
+use crate::EfiSystemTable;
+use crate::EfiVoid;
+
+pub const EFI_ACPI_10_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xeb9d2d30,
+    data1: 0x2d88,
+    data2: 0x11d3,
+    data3: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+};
+
+pub const EFI_ACPI_20_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0x8868e871,
+    data1: 0xe4f1,
+    data2: 0x11d3,
+    data3: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+};
+
+pub const SMBIOS_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xeb9d2d31,
+    data1: 0x2d88,
+    data2: 0x11d3,
+    data3: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+};
+
+pub const SMBIOS3_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xf2fd1544,
+    data1: 0x9794,
+    data2: 0x4a2c,
+    data3: [0x99, 0x2e, 0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94],
+};
+
+pub const EFI_DTB_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xb1b621d5,
+    data1: 0xf19c,
+    data2: 0x41a5,
+    data3: [0x83, 0x0b, 0xd9, 0x15, 0x2c, 0x69, 0xaa, 0xe0],
+};
+
+pub const EFI_MEMORY_ATTRIBUTES_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xdcfa911d,
+    data1: 0x26eb,
+    data2: 0x469f,
+    data3: [0xa2, 0x20, 0x38, 0xb7, 0xdc, 0x46, 0x12, 0x20],
+};
+
+// 知っているGUIDの名前(表示用)
+pub fn guid_name(guid: &EfiGuid) -> Option<&'static str> {
+    match *guid {
+        EFI_ACPI_10_TABLE_GUID => Some("ACPI 1.0"),
+        EFI_ACPI_20_TABLE_GUID => Some("ACPI 2.0"),
+        SMBIOS_TABLE_GUID => Some("SMBIOS"),
+        SMBIOS3_TABLE_GUID => Some("SMBIOS3"),
+        EFI_DTB_TABLE_GUID => Some("Device Tree"),
+        EFI_MEMORY_ATTRIBUTES_TABLE_GUID => Some("Memory Attributes"),
+        _ => None,
+    }
+}
+
+#[repr(C)]
+pub struct EfiConfigurationTable {
+    pub vendor_guid: EfiGuid,
+    pub vendor_table: *const EfiVoid,
+}
+
+const _: () = assert!(offset_of!(EfiConfigurationTable, vendor_table) == 16);
+const _: () = assert!(size_of::<EfiConfigurationTable>() == 24);
+
+impl EfiSystemTable {
+    // Configuration Tableの全エントリ
+    pub fn configuration_tables(&self) -> &[EfiConfigurationTable] {
+        if self.configuration_table.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.configuration_table, self.number_of_table_entries) }
+    }
+
+    // vendor_guidが一致する最初のテーブルの場所
+    pub fn find_configuration_table(&self, guid: &EfiGuid) -> Option<*const EfiVoid> {
+        self.configuration_tables()
+            .iter()
+            .find(|e| e.vendor_guid == *guid && !e.vendor_table.is_null())
+            .map(|e| e.vendor_table)
+    }
+}
+
+// ACPIやSMBIOSのチェックサムは、範囲のバイトを全部足すと(下位8ビットが)0になるように作られている
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+// 構造体の先頭からlenバイトを読むためのスライス
+unsafe fn bytes_of<T>(p: &T, len: usize) -> &[u8] {
+    slice::from_raw_parts(p as *const T as *const u8, len)
+}
+
+// ACPIのRSDP(Root System Description Pointer)
+// revisionが0(ACPI 1.0)のときはrsdt_addressまでの20バイトしかない
+// その後ろを読まないように、参照ではなく値としてコピーして持つ(無い部分は0のまま)
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct AcpiRsdp {
+    pub signature: [u8; 8], // "RSD PTR "
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    // ここから後ろはrevisionが2以上のときだけある
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+const _: () = assert!(offset_of!(AcpiRsdp, revision) == 15);
+const _: () = assert!(offset_of!(AcpiRsdp, rsdt_address) == 16);
+const _: () = assert!(offset_of!(AcpiRsdp, length) == 20);
+const _: () = assert!(offset_of!(AcpiRsdp, xsdt_address) == 24);
+const _: () = assert!(offset_of!(AcpiRsdp, extended_checksum) == 32);
+
+impl AcpiRsdp {
+    const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    const V1_LENGTH: usize = 20;
+    // 構造体は最後に詰め物が入るので、size_ofより短い
+    const V2_LENGTH: usize = 36;
+
+    // 先頭の20バイトのシグネチャとチェックサムを確かめて、revisionが2以上のときだけ後ろの部分を読む
+    unsafe fn from_ptr(p: *const EfiVoid) -> Option<AcpiRsdp> {
+        let v1 = slice::from_raw_parts(p, Self::V1_LENGTH);
+        if &v1[..Self::SIGNATURE.len()] != Self::SIGNATURE || !checksum_ok(v1) {
+            return None;
+        }
+        let copy_length = if v1[offset_of!(AcpiRsdp, revision)] >= 2 {
+            let length = (p.add(offset_of!(AcpiRsdp, length)) as *const u32).read_unaligned() as usize;
+            if length < Self::V2_LENGTH || !checksum_ok(slice::from_raw_parts(p, length)) {
+                return None;
+            }
+            Self::V2_LENGTH
+        } else {
+            Self::V1_LENGTH
+        };
+        let mut rsdp = AcpiRsdp::default();
+        ptr::copy_nonoverlapping(p, &mut rsdp as *mut AcpiRsdp as *mut u8, copy_length);
+        Some(rsdp)
+    }
+
+    // XSDTがあればXSDTを、なければRSDTを使う
+    pub fn xsdt(&self) -> Option<u64> {
+        (self.revision >= 2 && self.xsdt_address != 0).then_some(self.xsdt_address)
+    }
+}
+
+impl fmt::Debug for AcpiRsdp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let oem_id = core::str::from_utf8(&self.oem_id).unwrap_or("?");
+        write!(f, "RSDP rev {} OEM {:?} RSDT {:#x}", self.revision, oem_id, self.rsdt_address)?;
+        if let Some(xsdt) = self.xsdt() {
+            write!(f, " XSDT {xsdt:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+// SMBIOS 2.xのエントリポイント(32ビットのテーブルアドレスを持つ)
+#[repr(C)]
+pub struct SmbiosEntryPoint {
+    pub anchor: [u8; 4], // "_SM_"
+    pub checksum: u8,
+    pub length: u8,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub max_structure_size: u16,
+    pub entry_point_revision: u8,
+    pub formatted_area: [u8; 5],
+    pub intermediate_anchor: [u8; 5], // "_DMI_"
+    pub intermediate_checksum: u8,
+    pub table_length: u16,
+    pub table_address: u32,
+    pub number_of_structures: u16,
+    pub bcd_revision: u8,
+}
+
+const _: () = assert!(offset_of!(SmbiosEntryPoint, intermediate_anchor) == 16);
+const _: () = assert!(offset_of!(SmbiosEntryPoint, table_length) == 22);
+const _: () = assert!(offset_of!(SmbiosEntryPoint, table_address) == 24);
+const _: () = assert!(offset_of!(SmbiosEntryPoint, bcd_revision) == 30);
+
+impl SmbiosEntryPoint {
+    unsafe fn from_ptr(p: *const EfiVoid) -> Option<&'static SmbiosEntryPoint> {
+        let ep = &*(p as *const SmbiosEntryPoint);
+        // チェックサムはエントリポイント全体と、"_DMI_"から後ろの15バイトの2つがある
+        let intermediate = &bytes_of(ep, 31)[16..];
+        (&ep.anchor == b"_SM_"
+            && &ep.intermediate_anchor == b"_DMI_"
+            && ep.length >= 31
+            && checksum_ok(bytes_of(ep, ep.length as usize))
+            && checksum_ok(intermediate))
+        .then_some(ep)
+    }
+}
+
+impl fmt::Debug for SmbiosEntryPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SMBIOS {}.{} table {:#x} ({} bytes, {} structures)",
+            self.major_version,
+            self.minor_version,
+            self.table_address,
+            self.table_length,
+            self.number_of_structures
+        )
+    }
+}
+
+// SMBIOS 3.xのエントリポイント(64ビットのテーブルアドレスを持つ)
+#[repr(C)]
+pub struct Smbios3EntryPoint {
+    pub anchor: [u8; 5], // "_SM3_"
+    pub checksum: u8,
+    pub length: u8,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub docrev: u8,
+    pub entry_point_revision: u8,
+    _reserved: u8,
+    pub table_max_size: u32,
+    pub table_address: u64,
+}
+
+const _: () = assert!(offset_of!(Smbios3EntryPoint, table_max_size) == 12);
+const _: () = assert!(offset_of!(Smbios3EntryPoint, table_address) == 16);
+const _: () = assert!(size_of::<Smbios3EntryPoint>() == 24);
+
+impl Smbios3EntryPoint {
+    unsafe fn from_ptr(p: *const EfiVoid) -> Option<&'static Smbios3EntryPoint> {
+        let ep = &*(p as *const Smbios3EntryPoint);
+        (&ep.anchor == b"_SM3_"
+            && ep.length as usize >= size_of::<Smbios3EntryPoint>()
+            && checksum_ok(bytes_of(ep, ep.length as usize)))
+        .then_some(ep)
+    }
+}
+
+impl fmt::Debug for Smbios3EntryPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SMBIOS {}.{}.{} table {:#x} (max {} bytes)",
+            self.major_version,
+            self.minor_version,
+            self.docrev,
+            self.table_address,
+            self.table_max_size
+        )
+    }
+}
+
+// Device Tree(FDT)のヘッダ。値はすべてビッグエンディアン
+#[repr(C)]
+pub struct FdtHeader {
+    magic: u32,
+    total_size: u32,
+}
+
+impl FdtHeader {
+    const MAGIC: u32 = 0xd00d_feed;
+
+    unsafe fn from_ptr(p: *const EfiVoid) -> Option<&'static FdtHeader> {
+        let fdt = &*(p as *const FdtHeader);
+        (u32::from_be(fdt.magic) == Self::MAGIC).then_some(fdt)
+    }
+
+    pub fn total_size(&self) -> u32 {
+        u32::from_be(self.total_size)
+    }
+}
+
+impl fmt::Debug for FdtHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device Tree at {:p} ({} bytes)", self, self.total_size())
+    }
+}
+
+// EFI Memory Attributes Table
+// Runtime Servicesのイメージを、セクションごとにどう保護すればよいか(RO: 書き込み禁止、XP: 実行禁止)を表す
+// BOOT_SERVICES_DATAに置かれていることがあるので、ExitBootServicesの前に写しておく
+#[repr(C)]
+pub struct EfiMemoryAttributesTable {
+    pub version: u32,
+    pub number_of_entries: u32,
+    pub descriptor_size: u32,
+    _reserved: u32,
+    // この後ろに、EfiMemoryDescriptorがdescriptor_sizeバイトごとに並んでいる
+}
+
+const _: () = assert!(offset_of!(EfiMemoryAttributesTable, descriptor_size) == 8);
+const _: () = assert!(size_of::<EfiMemoryAttributesTable>() == 16);
+
+impl EfiMemoryAttributesTable {
+    unsafe fn from_ptr(p: *const EfiVoid) -> Option<&'static EfiMemoryAttributesTable> {
+        let table = &*(p as *const EfiMemoryAttributesTable);
+        (table.version >= 1 && table.descriptor_size as usize >= size_of::<EfiMemoryDescriptor>())
+            .then_some(table)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = EfiMemoryDescriptor> + '_ {
+        let base = unsafe { (self as *const Self).add(1) as *const u8 };
+        (0..self.number_of_entries as usize).map(move |i| unsafe {
+            (base.add(i * self.descriptor_size as usize) as *const EfiMemoryDescriptor).read_unaligned()
+        })
+    }
+}
+
+impl fmt::Debug for EfiMemoryAttributesTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Memory Attributes Table v{} ({} entries)", self.version, self.number_of_entries)
+    }
+}
+
+// Configuration Tableから見つけた、ハードウェアを調べる起点になるテーブル
+// 見つからなかったものや、チェックサムが合わなかったものはNoneになる
+#[derive(Default)]
+pub struct SystemTables {
+    pub acpi_rsdp: Option<AcpiRsdp>,
+    pub smbios: Option<&'static SmbiosEntryPoint>,
+    pub smbios3: Option<&'static Smbios3EntryPoint>,
+    pub device_tree: Option<&'static FdtHeader>,
+    pub memory_attributes: Option<&'static EfiMemoryAttributesTable>,
+}
+
+impl SystemTables {
+    pub fn from_system_table(efi_system_table: &EfiSystemTable) -> SystemTables {
+        let find = |guid| efi_system_table.find_configuration_table(guid);
+        unsafe {
+            SystemTables {
+                // ACPI 2.0のRSDP(XSDTがある)を優先する
+                acpi_rsdp: find(&EFI_ACPI_20_TABLE_GUID)
+                    .and_then(|p| AcpiRsdp::from_ptr(p))
+                    .or_else(|| find(&EFI_ACPI_10_TABLE_GUID).and_then(|p| AcpiRsdp::from_ptr(p))),
+                smbios: find(&SMBIOS_TABLE_GUID).and_then(|p| SmbiosEntryPoint::from_ptr(p)),
+                smbios3: find(&SMBIOS3_TABLE_GUID).and_then(|p| Smbios3EntryPoint::from_ptr(p)),
+                device_tree: find(&EFI_DTB_TABLE_GUID).and_then(|p| FdtHeader::from_ptr(p)),
+                memory_attributes: find(&EFI_MEMORY_ATTRIBUTES_TABLE_GUID)
+                    .and_then(|p| EfiMemoryAttributesTable::from_ptr(p)),
+            }
+        }
+    }
+}
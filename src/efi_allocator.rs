@@ -0,0 +1,101 @@
+// Boot Servicesのメモリ確保機能を使うアロケータ
+// allocクレート(Vec, String, Boxなど)をefi_mainから使えるようにする
+// ExitBootServicesの後はカーネルのヒープ(heap.rs)に任せる
+// それより前に確保したメモリは解放せず、LOADER_DATAとして残る
+
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use crate::boot_services_active;
+use crate::heap::KERNEL_HEAP;
+use crate::EfiAllocateType;
+use crate::EfiBootServiceTable;
+use crate::EfiMemoryType;
+
+// AllocatePoolが返すアドレスはこの境界に揃っている
+const POOL_ALIGN: usize = 8;
+// AllocatePagesが返すアドレスはページの境界に揃っている
+const PAGE_SIZE: usize = 4096;
+
+pub struct EfiBootServicesAllocator {
+    boot_services: AtomicPtr<EfiBootServiceTable>,
+}
+
+impl EfiBootServicesAllocator {
+    pub const fn new() -> Self {
+        Self {
+            boot_services: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    // 確保を始める前に、efi_mainで一度だけ呼ぶ
+    pub fn init(&self, boot_services: &'static EfiBootServiceTable) {
+        self.boot_services.store(
+            boot_services as *const EfiBootServiceTable as *mut EfiBootServiceTable,
+            Ordering::SeqCst,
+        );
+    }
+
+    fn boot_services(&self) -> Option<&EfiBootServiceTable> {
+        if !boot_services_active() {
+            return None;
+        }
+        unsafe { self.boot_services.load(Ordering::SeqCst).as_ref() }
+    }
+
+    // LOADER_DATAとしてページを確保する。Boot Servicesが終了した後はNone
+    pub fn allocate_pages(&self, pages: usize) -> Option<u64> {
+        self.boot_services()?
+            .allocate_pages(EfiAllocateType::AnyPages, EfiMemoryType::LOADER_DATA, pages)
+            .ok()
+    }
+}
+
+fn pages_for(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+// 8バイトより大きい境界が必要なときはページ単位で確保する
+// 4KiBより大きい境界は扱えないので、確保に失敗したことにする
+unsafe impl GlobalAlloc for EfiBootServicesAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(boot_services) = self.boot_services() else {
+            return KERNEL_HEAP.alloc(layout);
+        };
+        if layout.align() <= POOL_ALIGN {
+            boot_services
+                .allocate_pool(EfiMemoryType::LOADER_DATA, layout.size())
+                .unwrap_or(null_mut())
+        } else if layout.align() <= PAGE_SIZE {
+            boot_services
+                .allocate_pages(
+                    EfiAllocateType::AnyPages,
+                    EfiMemoryType::LOADER_DATA,
+                    pages_for(layout.size()),
+                )
+                .map_or(null_mut(), |address| address as *mut u8)
+        } else {
+            null_mut()
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if KERNEL_HEAP.contains(ptr) {
+            return KERNEL_HEAP.dealloc(ptr, layout);
+        }
+        let Some(boot_services) = self.boot_services() else {
+            return;
+        };
+        if layout.align() <= POOL_ALIGN {
+            let _ = boot_services.free_pool(ptr);
+        } else {
+            let _ = boot_services.free_pages(ptr as u64, pages_for(layout.size()));
+        }
+    }
+}
+
+#[cfg_attr(feature = "efi_pool_allocator", global_allocator)]
+pub static EFI_ALLOCATOR: EfiBootServicesAllocator = EfiBootServicesAllocator::new();
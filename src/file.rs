@@ -0,0 +1,332 @@
+// UEFIのSimple File System ProtocolとFile Protocol
+// このプログラムが読み込まれたボリューム(ESP)のファイルを読み書きせずに参照する
+// Boot Servicesが終了するまでの間だけ使える
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::offset_of;
+use core::mem::size_of;
+use core::ptr::null;
+
+use crate::boot_services_active;
+use crate::loaded_image::loaded_image;
+use crate::to_ucs2;
+use crate::EfiBootServiceTable;
+use crate::EfiGuid;
+use crate::EfiHandle;
+use crate::EfiResult;
+use crate::EfiStatus;
+use crate::EfiTime;
+use crate::EfiVoid;
+
+pub const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: EfiGuid = EfiGuid {
+    data0: 0x964e5b22,
+    data1: 0x6459,
+    data2: 0x11d2,
+    data3: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+pub const EFI_FILE_INFO_GUID: EfiGuid = EfiGuid {
+    data0: 0x09576e92,
+    data1: 0x6d3f,
+    data2: 0x11d2,
+    data3: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+// Openに渡すモード。読み込みだけを使う
+const EFI_FILE_MODE_READ: u64 = 0x1;
+
+#[repr(C)]
+pub struct EfiSimpleFileSystemProtocol {
+    pub revision: u64,
+    open_volume:
+        extern "win64" fn(this: *const Self, root: *mut *const EfiFileProtocol) -> EfiStatus,
+}
+
+const _: () = assert!(offset_of!(EfiSimpleFileSystemProtocol, open_volume) == 8);
+
+#[repr(C)]
+pub struct EfiFileProtocol {
+    pub revision: u64,
+    open: extern "win64" fn(
+        this: *const Self,
+        new_handle: *mut *const EfiFileProtocol,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> EfiStatus,
+    close: extern "win64" fn(this: *const Self) -> EfiStatus,
+    _reserved0: [u64; 1], // Delete
+    read: extern "win64" fn(
+        this: *const Self,
+        buffer_size: *mut usize,
+        buffer: *mut EfiVoid,
+    ) -> EfiStatus,
+    _reserved1: [u64; 3], // Write, GetPosition, SetPosition
+    get_info: extern "win64" fn(
+        this: *const Self,
+        information_type: *const EfiGuid,
+        buffer_size: *mut usize,
+        buffer: *mut EfiVoid,
+    ) -> EfiStatus,
+    _reserved2: [u64; 2], // SetInfo, Flush
+}
+
+const _: () = assert!(offset_of!(EfiFileProtocol, open) == 8);
+const _: () = assert!(offset_of!(EfiFileProtocol, read) == 32);
+const _: () = assert!(offset_of!(EfiFileProtocol, get_info) == 64);
+
+// ファイルの属性
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct EfiFileAttribute(pub u64);
+
+#[allow(dead_code)]
+impl EfiFileAttribute {
+    pub const READ_ONLY: u64 = 0x01;
+    pub const HIDDEN: u64 = 0x02;
+    pub const SYSTEM: u64 = 0x04;
+    pub const DIRECTORY: u64 = 0x10;
+    pub const ARCHIVE: u64 = 0x20;
+
+    pub fn is_directory(self) -> bool {
+        self.0 & Self::DIRECTORY != 0
+    }
+}
+
+// EFI_FILE_INFOの固定長の部分。この後ろにNUL終端されたUCS-2のファイル名が続く
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EfiFileInfoHeader {
+    size: u64,
+    file_size: u64,
+    physical_size: u64,
+    create_time: EfiTime,
+    last_access_time: EfiTime,
+    modification_time: EfiTime,
+    attribute: u64,
+}
+
+const _: () = assert!(size_of::<EfiFileInfoHeader>() == 80);
+
+// ファイルやディレクトリの情報
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub file_size: u64,
+    pub physical_size: u64,
+    pub create_time: EfiTime,
+    pub modification_time: EfiTime,
+    pub attribute: EfiFileAttribute,
+    pub file_name: String,
+}
+
+impl FileInfo {
+    // ファームウェアが書き込んだEFI_FILE_INFOを読む
+    fn parse(buf: &[u8]) -> EfiResult<FileInfo> {
+        if buf.len() < size_of::<EfiFileInfoHeader>() {
+            return Err(EfiStatus::BAD_BUFFER_SIZE);
+        }
+        // Vec<u8>の中身は8バイト境界に揃っているとは限らないので、read_unalignedで読む
+        let header =
+            unsafe { (buf.as_ptr() as *const EfiFileInfoHeader).read_unaligned() };
+        let name = buf[size_of::<EfiFileInfoHeader>()..]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0);
+        let file_name = char::decode_utf16(name)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Ok(FileInfo {
+            file_size: header.file_size,
+            physical_size: header.physical_size,
+            create_time: header.create_time,
+            modification_time: header.modification_time,
+            attribute: EfiFileAttribute(header.attribute),
+            file_name,
+        })
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attribute.is_directory()
+    }
+}
+
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_directory() {
+            write!(f, "{}  {}/", self.modification_time, self.file_name)
+        } else {
+            write!(
+                f,
+                "{}  {} ({} bytes)",
+                self.modification_time, self.file_name, self.file_size
+            )
+        }
+    }
+}
+
+// パスをFile Protocolに渡せるNUL終端のUCS-2に変換する
+// 区切り文字は'/'でも'\'でも良い
+fn to_ucs2_path(path: &str) -> Vec<u16> {
+    to_ucs2(&path.replace('/', "\\"))
+}
+
+// 開いているファイルかディレクトリ。dropすると閉じる
+pub struct EfiFile {
+    protocol: &'static EfiFileProtocol,
+}
+
+impl EfiFile {
+    // このファイル(ディレクトリ)からの相対パスでファイルを読み込み用に開く
+    pub fn open(&self, path: &str) -> EfiResult<EfiFile> {
+        let path = to_ucs2_path(path);
+        let mut new_handle = null::<EfiFileProtocol>();
+        (self.protocol.open)(
+            self.protocol,
+            &mut new_handle,
+            path.as_ptr(),
+            EFI_FILE_MODE_READ,
+            0,
+        )
+        .into_result()?;
+        Ok(EfiFile {
+            protocol: unsafe { &*new_handle },
+        })
+    }
+
+    // bufに読み込んで、読み込んだバイト数を返す。0ならファイルの終わり
+    pub fn read(&mut self, buf: &mut [u8]) -> EfiResult<usize> {
+        let mut size = buf.len();
+        (self.protocol.read)(self.protocol, &mut size, buf.as_mut_ptr()).into_result()?;
+        Ok(size)
+    }
+
+    // 今の位置からファイルの終わりまでをすべて読み込む
+    pub fn read_to_end(&mut self) -> EfiResult<Vec<u8>> {
+        let file_size = self.info()?.file_size as usize;
+        let mut data = vec![0u8; file_size];
+        let mut len = 0;
+        while len < data.len() {
+            let read = self.read(&mut data[len..])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+
+    // ファイルの大きさや属性などを取得する
+    pub fn info(&self) -> EfiResult<FileInfo> {
+        let mut buf = vec![0u8; size_of::<EfiFileInfoHeader>() + 128];
+        loop {
+            let mut size = buf.len();
+            match (self.protocol.get_info)(
+                self.protocol,
+                &EFI_FILE_INFO_GUID,
+                &mut size,
+                buf.as_mut_ptr(),
+            )
+            .into_result()
+            {
+                Ok(()) => return FileInfo::parse(&buf[..size]),
+                // 名前が長くて入りきらなかったときは、必要な大きさで取り直す
+                Err(EfiStatus::BUFFER_TOO_SMALL) => buf.resize(size, 0),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // ディレクトリの中身を列挙する
+    pub fn read_dir(&mut self) -> ReadDir {
+        ReadDir {
+            dir: self,
+            done: false,
+        }
+    }
+
+    // ディレクトリからエントリを1つ読む。もうなければNone
+    fn read_dir_entry(&mut self) -> EfiResult<Option<FileInfo>> {
+        let mut buf = vec![0u8; size_of::<EfiFileInfoHeader>() + 128];
+        loop {
+            let mut size = buf.len();
+            match (self.protocol.read)(self.protocol, &mut size, buf.as_mut_ptr()).into_result() {
+                Ok(()) if size == 0 => return Ok(None),
+                Ok(()) => return FileInfo::parse(&buf[..size]).map(Some),
+                Err(EfiStatus::BUFFER_TOO_SMALL) => buf.resize(size, 0),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for EfiFile {
+    fn drop(&mut self) {
+        if boot_services_active() {
+            let _ = (self.protocol.close)(self.protocol);
+        }
+    }
+}
+
+pub struct ReadDir<'a> {
+    dir: &'a mut EfiFile,
+    done: bool,
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = EfiResult<FileInfo>;
+
+    fn next(&mut self) -> Option<EfiResult<FileInfo>> {
+        if self.done {
+            return None;
+        }
+        let entry = self.dir.read_dir_entry().transpose();
+        // エラーが起きたら、それ以上は読まない
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+// このプログラムが読み込まれたボリュームのルートディレクトリを開く
+pub fn open_boot_volume(
+    boot_services: &EfiBootServiceTable,
+    image_handle: EfiHandle,
+) -> EfiResult<EfiFile> {
+    let image = loaded_image(boot_services, image_handle)?;
+    let fs = boot_services
+        .handle_protocol(image.device_handle, &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID)?
+        as *const EfiSimpleFileSystemProtocol;
+    let fs = unsafe { &*fs };
+    let mut root = null::<EfiFileProtocol>();
+    (fs.open_volume)(fs, &mut root).into_result()?;
+    Ok(EfiFile {
+        protocol: unsafe { &*root },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_runner::image_handle;
+    use crate::test_runner::system_table;
+
+    #[test_case]
+    fn open_boot_volume_reads_own_image() {
+        let root = open_boot_volume(system_table().boot_services, image_handle())
+            .expect("open_boot_volume failed");
+        let mut file = root.open("EFI/BOOT/BOOTX64.EFI").expect("BOOTX64.EFI not found");
+        assert!(!file.info().unwrap().is_directory());
+        let data = file.read_to_end().unwrap();
+        assert_eq!(data.get(..2), Some(&b"MZ"[..]));
+    }
+
+    #[test_case]
+    fn to_ucs2_path_uses_backslash() {
+        assert_eq!(to_ucs2_path("a/b"), [b'a' as u16, b'\\' as u16, b'b' as u16, 0]);
+    }
+}
@@ -0,0 +1,420 @@
+// 物理ページ(フレーム)のアロケータ
+// ページごとに1ビットのビットマップで管理する(1なら使用中か、使ってはいけないページ)
+// もう1つのビットマップで、このアロケータが扱うページ(空きか、ここから確保したもの)を覚えておく
+// 一度使えなくした(reserve_rangeした)ページは、あとでadd_free_rangeされても使わない
+// ExitBootServicesの後に、最新のメモリマップのCONVENTIONAL_MEMORYから作り、
+// その後でBoot ServicesやLOADERが使っていた領域も取り込む
+// ヒープやページテーブル、DMAのバッファなど、物理メモリが必要なものはここから確保する
+
+use core::fmt;
+use core::mem::size_of;
+
+use crate::mutex::Mutex;
+use crate::x86::read_cr3;
+use crate::x86::read_gdtr;
+use crate::x86::read_idtr;
+use crate::x86::read_rsp;
+use crate::EfiMemoryDescriptor;
+use crate::EfiMemoryType;
+use crate::MemoryMapHolder;
+use crate::Result;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+const BITS_PER_WORD: u64 = u64::BITS as u64;
+
+// ExitBootServicesの後にOSが自由に使ってよい種類。これ以外の領域は最初から最後まで使わない
+const RAM_MEMORY_TYPES: [EfiMemoryType; 5] = [
+    EfiMemoryType::CONVENTIONAL_MEMORY,
+    EfiMemoryType::LOADER_CODE,
+    EfiMemoryType::LOADER_DATA,
+    EfiMemoryType::BOOT_SERVICE_CODE,
+    EfiMemoryType::BOOT_SERVICE_DATA,
+];
+
+impl EfiMemoryDescriptor {
+    fn physical_end(&self) -> u64 {
+        self.physical_start + self.number_of_pages * PAGE_SIZE
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        (self.physical_start..self.physical_end()).contains(&address)
+    }
+}
+
+// i番目のビットが、物理アドレスi * PAGE_SIZEから始まるページ
+fn get_bit(bitmap: &[u64], page: u64) -> bool {
+    bitmap[(page / BITS_PER_WORD) as usize] & (1 << (page % BITS_PER_WORD)) != 0
+}
+
+// ビットが変わったらtrueを返す
+fn set_bit(bitmap: &mut [u64], page: u64, value: bool) -> bool {
+    let word = &mut bitmap[(page / BITS_PER_WORD) as usize];
+    let bit = 1 << (page % BITS_PER_WORD);
+    let changed = (*word & bit != 0) != value;
+    if value {
+        *word |= bit;
+    } else {
+        *word &= !bit;
+    }
+    changed
+}
+
+pub struct FrameAllocator {
+    // 1なら使用中か、使ってはいけないページ
+    bitmap: &'static mut [u64],
+    // 1ならこのアロケータが扱うページ。使ってはいけないページと、確保したページを区別する
+    managed: &'static mut [u64],
+    // 1なら使ってはいけないと決めたページ(0番地、ビットマップ自身、MMIOなど)
+    reserved: &'static mut [u64],
+    free_pages: u64,
+    // 扱っているページの数(確保済みのものを含む)
+    managed_pages: u64,
+}
+
+impl FrameAllocator {
+    // 最初はすべてのページが使えない
+    // bitmapsは3等分して、使用中・扱っている・使ってはいけない、の3つのビットマップにする
+    pub fn new(bitmaps: &'static mut [u64]) -> FrameAllocator {
+        assert_eq!(bitmaps.len() % 3, 0);
+        let words = bitmaps.len() / 3;
+        let (bitmap, rest) = bitmaps.split_at_mut(words);
+        let (managed, reserved) = rest.split_at_mut(words);
+        bitmap.fill(!0);
+        managed.fill(0);
+        reserved.fill(0);
+        FrameAllocator {
+            bitmap,
+            managed,
+            reserved,
+            free_pages: 0,
+            managed_pages: 0,
+        }
+    }
+
+    // ビットマップで扱えるページの数
+    fn capacity(&self) -> u64 {
+        self.bitmap.len() as u64 * BITS_PER_WORD
+    }
+
+    fn is_used(&self, page: u64) -> bool {
+        get_bit(self.bitmap, page)
+    }
+
+    fn set_used(&mut self, page: u64, used: bool) -> bool {
+        set_bit(self.bitmap, page, used)
+    }
+
+    fn is_managed(&self, page: u64) -> bool {
+        get_bit(self.managed, page)
+    }
+
+    // [start, start + size)に完全に含まれるページを使えるようにする
+    pub fn add_free_range(&mut self, start: u64, size: u64) {
+        let first = start.div_ceil(PAGE_SIZE);
+        let end = ((start + size) / PAGE_SIZE).min(self.capacity());
+        for page in first..end {
+            // 使ってはいけないページや、確保済みのページはそのままにしておく
+            if get_bit(self.reserved, page) {
+                continue;
+            }
+            if set_bit(self.managed, page, true) {
+                self.managed_pages += 1;
+                if self.set_used(page, false) {
+                    self.free_pages += 1;
+                }
+            }
+        }
+    }
+
+    // [start, start + size)に少しでもかかるページを使えなくする。このアロケータではもう扱わない
+    pub fn reserve_range(&mut self, start: u64, size: u64) {
+        let first = start / PAGE_SIZE;
+        let end = (start + size).div_ceil(PAGE_SIZE).min(self.capacity());
+        for page in first..end {
+            set_bit(self.reserved, page, true);
+            if self.set_used(page, true) {
+                self.free_pages -= 1;
+            }
+            if set_bit(self.managed, page, false) {
+                self.managed_pages -= 1;
+            }
+        }
+    }
+
+    pub fn allocate(&mut self, pages: u64) -> Result<u64> {
+        self.allocate_aligned(pages, PAGE_SIZE)
+    }
+
+    // 物理アドレスが連続したpagesページを、alignの倍数のアドレスから確保する(最初に見つかったところ)
+    pub fn allocate_aligned(&mut self, pages: u64, align: u64) -> Result<u64> {
+        if pages == 0 {
+            return Err("Cannot allocate 0 pages");
+        }
+        if !align.is_power_of_two() || align < PAGE_SIZE {
+            return Err("Alignment must be a power of two and at least a page");
+        }
+        let step = align / PAGE_SIZE;
+        let mut start = 0;
+        while start + pages <= self.capacity() {
+            // 64ページすべてが使用中の場所はまとめて飛ばす
+            if self.bitmap[(start / BITS_PER_WORD) as usize] == !0 {
+                start = (start / BITS_PER_WORD + 1) * BITS_PER_WORD;
+                start = start.next_multiple_of(step);
+                continue;
+            }
+            // 後ろから調べて、使用中のページがあればその次から探し直す
+            match (start..start + pages).rev().find(|page| self.is_used(*page)) {
+                Some(used) => start = (used + 1).next_multiple_of(step),
+                None => {
+                    for page in start..start + pages {
+                        self.set_used(page, true);
+                    }
+                    self.free_pages -= pages;
+                    return Ok(start * PAGE_SIZE);
+                }
+            }
+        }
+        Err("Out of physical memory")
+    }
+
+    pub fn free(&mut self, address: u64, pages: u64) -> Result<()> {
+        if address % PAGE_SIZE != 0 {
+            return Err("Address is not page aligned");
+        }
+        let first = address / PAGE_SIZE;
+        // 使ってはいけないページ(0番地、ビットマップ、MMIOなど)も使用中になっているので、扱っているかも確かめる
+        if first + pages > self.capacity()
+            || !(first..first + pages).all(|page| self.is_used(page) && self.is_managed(page))
+        {
+            return Err("Freeing pages that are not allocated");
+        }
+        for page in first..first + pages {
+            self.set_used(page, false);
+        }
+        self.free_pages += pages;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> FrameAllocatorStats {
+        FrameAllocatorStats {
+            free_pages: self.free_pages,
+            managed_pages: self.managed_pages,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameAllocatorStats {
+    pub free_pages: u64,
+    pub managed_pages: u64,
+}
+
+impl fmt::Display for FrameAllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB free of {} KiB",
+            self.free_pages * PAGE_SIZE / 1024,
+            self.managed_pages * PAGE_SIZE / 1024
+        )
+    }
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+// ExitBootServicesの後に一度だけ呼ぶ
+// 3つのビットマップは、いちばん大きい物理アドレスまでを扱える大きさにして、CONVENTIONAL_MEMORYの中に並べて置く
+pub fn init(memory_map: &MemoryMapHolder) -> Result<()> {
+    let end = memory_map
+        .iter()
+        .filter(|e| RAM_MEMORY_TYPES.contains(&e.memory_type))
+        .map(|e| e.physical_end())
+        .max()
+        .ok_or("No usable memory")?;
+    let words = (end / PAGE_SIZE).div_ceil(BITS_PER_WORD) as usize;
+    let bitmap_size = (3 * words * size_of::<u64>()) as u64;
+    let place = memory_map
+        .iter()
+        .find(|e| {
+            e.memory_type == EfiMemoryType::CONVENTIONAL_MEMORY
+                && e.physical_start != 0
+                && e.number_of_pages * PAGE_SIZE >= bitmap_size
+        })
+        .ok_or("No room for the frame bitmap")?;
+    let bitmaps = unsafe { core::slice::from_raw_parts_mut(place.physical_start as *mut u64, 3 * words) };
+    let mut allocator = FrameAllocator::new(bitmaps);
+    for e in memory_map.iter() {
+        if e.memory_type == EfiMemoryType::CONVENTIONAL_MEMORY {
+            allocator.add_free_range(e.physical_start, e.number_of_pages * PAGE_SIZE);
+        }
+    }
+    // 0番地のページはNULLと区別できないので使わない
+    allocator.reserve_range(0, PAGE_SIZE);
+    allocator.reserve_range(place.physical_start, bitmap_size);
+    // ACPIやRuntime Services、MMIOなどの領域は、万一ほかの領域と重なっていても使わない
+    for e in memory_map.iter() {
+        if !RAM_MEMORY_TYPES.contains(&e.memory_type) {
+            allocator.reserve_range(e.physical_start, e.number_of_pages * PAGE_SIZE);
+        }
+    }
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+    Ok(())
+}
+
+// メモリマップに載っていない領域(フレームバッファなど)を使わないようにする
+pub fn reserve(start: u64, size: u64) -> Result<()> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .ok_or("Frame allocator is not initialized")?
+        .reserve_range(start, size);
+    Ok(())
+}
+
+// memory_typesの領域も使えるようにして、増えた空きページの数を返す
+// ファームウェアが用意して今も使っているもの(スタック、GDTとIDT、ページテーブル)と、keepの範囲は除く
+pub fn reclaim(
+    memory_map: &MemoryMapHolder,
+    memory_types: &[EfiMemoryType],
+    keep: impl IntoIterator<Item = (u64, u64)>,
+) -> Result<u64> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("Frame allocator is not initialized")?;
+    let free_pages = allocator.free_pages;
+    for e in memory_map.iter() {
+        if memory_types.contains(&e.memory_type) {
+            allocator.add_free_range(e.physical_start, e.number_of_pages * PAGE_SIZE);
+        }
+    }
+    let rsp = read_rsp();
+    if let Some(stack) = memory_map.iter().find(|e| e.contains(rsp)) {
+        allocator.reserve_range(stack.physical_start, stack.number_of_pages * PAGE_SIZE);
+    }
+    for (start, size) in [read_gdtr(), read_idtr()].into_iter().chain(keep) {
+        allocator.reserve_range(start, size);
+    }
+    reserve_page_table(allocator, read_cr3() & PAGE_TABLE_ADDRESS_MASK, 4);
+    Ok(allocator.free_pages.saturating_sub(free_pages))
+}
+
+// ページテーブルのエントリのうち、次の段のテーブル(またはページ)の物理アドレスの部分
+const PAGE_TABLE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const PAGE_TABLE_PRESENT: u64 = 1 << 0;
+// PDPTやPDのエントリで立っていれば、1GiBや2MiBのページそのものを指している
+const PAGE_TABLE_PAGE_SIZE: u64 = 1 << 7;
+
+// tableから下のページテーブル(level 4がPML4、1がPT)が置かれているページを使わないようにする
+fn reserve_page_table(allocator: &mut FrameAllocator, table: u64, level: u32) {
+    allocator.reserve_range(table, PAGE_SIZE);
+    if level == 1 {
+        return;
+    }
+    let entries = unsafe { core::slice::from_raw_parts(table as *const u64, 512) };
+    for entry in entries {
+        let is_page = level <= 3 && entry & PAGE_TABLE_PAGE_SIZE != 0;
+        if entry & PAGE_TABLE_PRESENT != 0 && !is_page {
+            reserve_page_table(allocator, entry & PAGE_TABLE_ADDRESS_MASK, level - 1);
+        }
+    }
+}
+
+// 物理アドレスが連続したpagesページを確保する
+pub fn allocate_frames(pages: u64) -> Result<u64> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .ok_or("Frame allocator is not initialized")?
+        .allocate(pages)
+}
+
+// alignの倍数の物理アドレスから、連続したpagesページを確保する(2MiBのページやDMAのバッファ用)
+#[allow(dead_code)]
+pub fn allocate_aligned_frames(pages: u64, align: u64) -> Result<u64> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .ok_or("Frame allocator is not initialized")?
+        .allocate_aligned(pages, align)
+}
+
+pub fn free_frames(address: u64, pages: u64) -> Result<()> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .ok_or("Frame allocator is not initialized")?
+        .free(address, pages)
+}
+
+pub fn stats() -> Option<FrameAllocatorStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(FrameAllocator::stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn allocator(pages: usize) -> FrameAllocator {
+        FrameAllocator::new(vec![0; 3 * pages.div_ceil(64)].leak())
+    }
+
+    #[test_case]
+    fn frame_allocator_uses_only_added_pages() {
+        let mut a = allocator(128);
+        a.add_free_range(0x1800, 0x4000);
+        // 端の欠けたページは使わないので、0x2000-0x5000の3ページだけ
+        assert_eq!(a.stats(), FrameAllocatorStats { free_pages: 3, managed_pages: 3 });
+        a.reserve_range(0x3000, 0x10);
+        assert_eq!(a.allocate(1), Ok(0x2000));
+        assert_eq!(a.allocate(2), Err("Out of physical memory"));
+        assert_eq!(a.allocate(1), Ok(0x4000));
+        assert_eq!(a.stats().free_pages, 0);
+        assert_eq!(a.free(0x2000, 1), Ok(()));
+        assert!(a.free(0x2000, 1).is_err());
+        assert_eq!(a.allocate(1), Ok(0x2000));
+    }
+
+    #[test_case]
+    fn frame_allocator_rejects_freeing_reserved_pages() {
+        let mut a = allocator(128);
+        a.add_free_range(0, 8 * PAGE_SIZE);
+        a.reserve_range(0, PAGE_SIZE);
+        a.reserve_range(0x3000, 2 * PAGE_SIZE);
+        assert_eq!(a.stats(), FrameAllocatorStats { free_pages: 5, managed_pages: 5 });
+        // 使ってはいけないページや、一度も使えるようにしていないページは解放できない
+        assert!(a.free(0, 1).is_err());
+        assert!(a.free(0x3000, 1).is_err());
+        assert!(a.free(0x10000, 1).is_err());
+        // 確保したページと使ってはいけないページにまたがっていてもだめ
+        assert_eq!(a.allocate(2), Ok(0x1000));
+        assert!(a.free(0x2000, 2).is_err());
+        assert_eq!(a.free(0x1000, 2), Ok(()));
+        assert_eq!(a.stats(), FrameAllocatorStats { free_pages: 5, managed_pages: 5 });
+    }
+
+    #[test_case]
+    fn frame_allocator_never_reuses_reserved_pages() {
+        let mut a = allocator(128);
+        a.reserve_range(0, PAGE_SIZE);
+        // reclaimがBoot Servicesの領域を取り込むときのように、同じページをあとから追加する
+        a.add_free_range(0, 4 * PAGE_SIZE);
+        assert_eq!(a.stats(), FrameAllocatorStats { free_pages: 3, managed_pages: 3 });
+        assert_eq!(a.allocate(3), Ok(0x1000));
+        assert_eq!(a.allocate(1), Err("Out of physical memory"));
+        assert!(a.free(0, 1).is_err());
+    }
+
+    #[test_case]
+    fn frame_allocator_allocates_contiguous_aligned_runs() {
+        let mut a = allocator(1024);
+        a.add_free_range(0x1000, 1023 * PAGE_SIZE);
+        assert_eq!(a.allocate(3), Ok(0x1000));
+        // 2MiBの境界に揃える必要があるので、0x200000から
+        assert_eq!(a.allocate_aligned(512, 0x20_0000), Ok(0x20_0000));
+        assert_eq!(a.allocate_aligned(1, 0x4000), Ok(0x4000));
+        assert!(a.allocate_aligned(1, 0x1800).is_err());
+        assert!(a.allocate(0).is_err());
+        assert_eq!(a.stats().free_pages, 1023 - 3 - 512 - 1);
+    }
+}
@@ -0,0 +1,248 @@
+// GOP(Graphics Output Protocol)の画面モードの列挙と切り替え
+
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::null;
+
+use crate::locate_graphic_protolocol;
+use crate::Color;
+use crate::EfiBootServiceTable;
+use crate::EfiGraphicsOutputProtocol;
+use crate::EfiGraphicsOutputProtocolPixelInfo;
+use crate::EfiResult;
+use crate::EfiStatus;
+use crate::EfiSystemTable;
+use crate::EfiVoid;
+use crate::Result;
+use crate::VramBufferInfo;
+
+// フレームバッファの1ピクセルがどう並んでいるか
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[repr(transparent)]
+pub struct EfiGraphicsPixelFormat(pub u32);
+
+impl EfiGraphicsPixelFormat {
+    // 1ピクセル4バイトで、メモリ上にR, G, B, 予約の順に並ぶ
+    pub const RGB_RESERVED_8BIT: EfiGraphicsPixelFormat = EfiGraphicsPixelFormat(0);
+    // 1ピクセル4バイトで、メモリ上にB, G, R, 予約の順に並ぶ
+    pub const BGR_RESERVED_8BIT: EfiGraphicsPixelFormat = EfiGraphicsPixelFormat(1);
+    // 各色の位置はpixel_informationのビットマスクで表される
+    pub const BIT_MASK: EfiGraphicsPixelFormat = EfiGraphicsPixelFormat(2);
+    // フレームバッファには直接書き込めず、Bltでしか描画できない
+    pub const BLT_ONLY: EfiGraphicsPixelFormat = EfiGraphicsPixelFormat(3);
+}
+
+impl fmt::Debug for EfiGraphicsPixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::RGB_RESERVED_8BIT => f.write_str("RGB"),
+            Self::BGR_RESERVED_8BIT => f.write_str("BGR"),
+            Self::BIT_MASK => f.write_str("BitMask"),
+            Self::BLT_ONLY => f.write_str("BltOnly"),
+            _ => write!(f, "Unknown({})", self.0),
+        }
+    }
+}
+
+// pixel_formatがBIT_MASKのとき、各色がピクセルのどのビットにあるかを表す
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EfiPixelBitmask {
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+}
+
+impl EfiPixelBitmask {
+    // 8ビットの色の値を、maskのビット幅に合わせて伸び縮みさせ、maskの位置に置く
+    fn place(mask: u32, value: u8) -> u32 {
+        if mask == 0 {
+            return 0;
+        }
+        let shift = mask.trailing_zeros();
+        let width = mask.count_ones();
+        let value = value as u32;
+        let scaled = if width >= 8 {
+            value << (width - 8)
+        } else {
+            value >> (8 - width)
+        };
+        (scaled << shift) & mask
+    }
+
+    fn encode(&self, color: Color) -> u32 {
+        Self::place(self.red_mask, color.r)
+            | Self::place(self.green_mask, color.g)
+            | Self::place(self.blue_mask, color.b)
+    }
+
+    // マスクのうち一番上のビットまで入るバイト数
+    fn bytes_per_pixel(&self) -> i64 {
+        let all = self.red_mask | self.green_mask | self.blue_mask | self.reserved_mask;
+        let bits = 32 - all.leading_zeros();
+        (bits as i64 + 7) / 8
+    }
+}
+
+// 描画のときに使う、フレームバッファのピクセルの並び方
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PixelLayout {
+    Rgb,
+    Bgr,
+    BitMask(EfiPixelBitmask),
+    // フレームバッファに直接書き込めないので、GOPのBltで描画する
+    BltOnly,
+}
+
+impl PixelLayout {
+    pub fn from_info(info: &EfiGraphicsOutputProtocolPixelInfo) -> PixelLayout {
+        match info.pixel_format {
+            EfiGraphicsPixelFormat::RGB_RESERVED_8BIT => PixelLayout::Rgb,
+            EfiGraphicsPixelFormat::BGR_RESERVED_8BIT => PixelLayout::Bgr,
+            EfiGraphicsPixelFormat::BIT_MASK if info.pixel_information.bytes_per_pixel() > 0 => {
+                PixelLayout::BitMask(info.pixel_information)
+            }
+            // 知らない形式のフレームバッファには書き込まない
+            _ => PixelLayout::BltOnly,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> i64 {
+        match self {
+            PixelLayout::BitMask(mask) => mask.bytes_per_pixel(),
+            _ => 4,
+        }
+    }
+
+    // 色をフレームバッファに書き込む値に変換する(リトルエンディアンで書き込む前提)
+    pub fn encode(&self, color: Color) -> u32 {
+        let (r, g, b) = (color.r as u32, color.g as u32, color.b as u32);
+        match self {
+            PixelLayout::Rgb => (b << 16) | (g << 8) | r,
+            PixelLayout::Bgr | PixelLayout::BltOnly => (r << 16) | (g << 8) | b,
+            PixelLayout::BitMask(mask) => mask.encode(color),
+        }
+    }
+}
+
+// Bltで使うピクセル。並びは常にB, G, R, 予約
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct EfiGraphicsOutputBltPixel {
+    pub blue: u8,
+    pub green: u8,
+    pub red: u8,
+    pub reserved: u8,
+}
+
+impl From<Color> for EfiGraphicsOutputBltPixel {
+    fn from(color: Color) -> Self {
+        EfiGraphicsOutputBltPixel {
+            blue: color.b,
+            green: color.g,
+            red: color.r,
+            reserved: 0,
+        }
+    }
+}
+
+// Bltの操作の種類(EfiBltVideoFill)。ほかにVideoToBltBuffer, BufferToVideo, VideoToVideoがある
+const EFI_BLT_VIDEO_FILL: u32 = 0;
+
+// QueryModeで取得した画面モード
+#[derive(Debug, Clone, Copy)]
+pub struct GopMode {
+    pub number: u32,
+    pub info: EfiGraphicsOutputProtocolPixelInfo,
+}
+
+pub struct GopModeIterator<'a, 'b> {
+    gp: &'b EfiGraphicsOutputProtocol<'a>,
+    boot_services: &'b EfiBootServiceTable,
+    next_mode: u32,
+}
+
+impl Iterator for GopModeIterator<'_, '_> {
+    type Item = GopMode;
+
+    fn next(&mut self) -> Option<GopMode> {
+        while self.next_mode < self.gp.mode.max_mode {
+            let number = self.next_mode;
+            self.next_mode += 1;
+            if let Ok(info) = self.gp.query_mode(self.boot_services, number) {
+                return Some(GopMode { number, info });
+            }
+        }
+        None
+    }
+}
+
+impl<'a> EfiGraphicsOutputProtocol<'a> {
+    // mode_number番の画面モードの情報を取得する
+    pub fn query_mode(
+        &self,
+        boot_services: &EfiBootServiceTable,
+        mode_number: u32,
+    ) -> EfiResult<EfiGraphicsOutputProtocolPixelInfo> {
+        let mut size_of_info = 0;
+        let mut info = null::<EfiGraphicsOutputProtocolPixelInfo>();
+        (self.query_mode)(self, mode_number, &mut size_of_info, &mut info).into_result()?;
+        if info.is_null() {
+            return Err(EfiStatus::DEVICE_ERROR);
+        }
+        // infoはファームウェアが確保したメモリなので、必要な分をコピーしてから解放する
+        // 将来のバージョンで構造体が大きくなっても、知っている部分だけ読めば良い
+        let result = if size_of_info >= size_of::<EfiGraphicsOutputProtocolPixelInfo>() {
+            Ok(unsafe { *info })
+        } else {
+            Err(EfiStatus::INCOMPATIBLE_VERSION)
+        };
+        boot_services.free_pool(info as *mut EfiVoid)?;
+        result
+    }
+
+    // 使える画面モードをすべて列挙する(情報が取れなかったモードは飛ばす)
+    pub fn modes<'b>(&'b self, boot_services: &'b EfiBootServiceTable) -> GopModeIterator<'a, 'b> {
+        GopModeIterator {
+            gp: self,
+            boot_services,
+            next_mode: 0,
+        }
+    }
+
+    // 条件に合う最初の画面モードを探す
+    pub fn find_mode(
+        &self,
+        boot_services: &EfiBootServiceTable,
+        mut pred: impl FnMut(&EfiGraphicsOutputProtocolPixelInfo) -> bool,
+    ) -> Option<GopMode> {
+        self.modes(boot_services).find(|mode| pred(&mode.info))
+    }
+
+    // 画面の矩形をcolorで塗りつぶす。BltOnlyの画面モードでも使える
+    pub fn blt_fill(&self, x: usize, y: usize, w: usize, h: usize, color: Color) -> EfiResult<()> {
+        let mut pixel = EfiGraphicsOutputBltPixel::from(color);
+        (self.blt)(self, &mut pixel, EFI_BLT_VIDEO_FILL, 0, 0, x, y, w, h, 0).into_result()
+    }
+
+    // 画面モードを切り替える。成功すると画面は黒でクリアされる
+    // フレームバッファのアドレスや大きさも変わりうるので、VramBufferInfoは作り直すこと
+    pub fn set_mode(&self, mode_number: u32) -> EfiResult<()> {
+        (self.set_mode)(self, mode_number).into_result()
+    }
+}
+
+// 条件に合う画面モードに切り替えて、新しいモードのVramBufferInfoを返す
+// 解像度で選ぶ場合は|info| info.horizontal_resolution == 1024 && ... のように指定する
+pub fn switch_vram_mode(
+    efi_system_table: &EfiSystemTable,
+    pred: impl FnMut(&EfiGraphicsOutputProtocolPixelInfo) -> bool,
+) -> Result<VramBufferInfo> {
+    let gp = locate_graphic_protolocol(efi_system_table)?;
+    let mode = gp
+        .find_mode(efi_system_table.boot_services, pred)
+        .ok_or("No matching graphics mode")?;
+    gp.set_mode(mode.number)?;
+    Ok(VramBufferInfo::from_gop(gp))
+}
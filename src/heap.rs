@@ -0,0 +1,677 @@
+// カーネルのヒープ
+// 2048バイトまでの小さいものは、大きさごとのキャッシュ(スラブ)から取り出す
+// スラブは1ページを同じ大きさのオブジェクトに切り分けたもので、空いたオブジェクトはリストでつないでおく
+// それより大きいものは、アドレス順に並べた空きブロックのリストから切り出す(first fit)
+// 解放したブロックは隣の空きブロックとまとめる
+// 足りなくなったら、Boot Servicesが使えるうちはAllocatePagesで、その後はframe_allocatorからページをもらって広げる
+// efi_pool_allocatorを無効にするとこれがグローバルアロケータになる
+// 有効なときも、ExitBootServicesの後はEFI_ALLOCATORがこちらに任せる
+//
+// heap_debugを有効にすると、壊れたヒープを調べるための仕掛けを入れる
+// - 確保したメモリの前後にレッドゾーンを置き、解放するときに書き換えられていないか確認する
+// - 解放したメモリを毒(POISON_FREE)で埋め、スラブから再び取り出すときに書き換えられていないか確認する
+// - 確保した場所(呼び出し元)を記録し、heapinfoで確保されたままのものを一覧する
+
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+#[cfg(feature = "heap_debug")]
+use crate::backtrace::frames;
+use crate::boot_services_active;
+use crate::efi_allocator::EFI_ALLOCATOR;
+use crate::frame_allocator;
+use crate::frame_allocator::PAGE_SIZE;
+use crate::mutex::Mutex;
+use crate::symbols::lookup;
+
+// すべてのブロックの先頭と大きさはこの倍数にする
+const BLOCK_ALIGN: usize = 16;
+// 一度に広げる最小の大きさ
+const MIN_GROW_SIZE: usize = 64 * 1024;
+// スラブから確保する大きさ。オブジェクトはその大きさの倍数のアドレスに置かれる
+const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const SLAB_SIZE: usize = PAGE_SIZE as usize;
+
+// heap_debugで使う値
+const POISON_FREE: u8 = 0x6b;
+#[cfg(feature = "heap_debug")]
+const REDZONE_BYTE: u8 = 0xfd;
+// 確保したメモリの後ろに置くレッドゾーンの大きさ
+#[cfg(feature = "heap_debug")]
+const REDZONE_SIZE: usize = 16;
+// 確保した場所として記録する呼び出し元の数
+const SITE_FRAMES: usize = 8;
+
+// 空きブロックの先頭に置く
+#[repr(C)]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+// スラブの空いているオブジェクトの先頭に置く
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// ページからもらった領域の先頭に置く。領域は返さないので、リストはつながっていく一方
+#[repr(C)]
+struct HeapRegion {
+    size: usize,
+    next: *mut HeapRegion,
+}
+
+const _: () = assert!(size_of::<FreeBlock>() == BLOCK_ALIGN);
+const _: () = assert!(size_of::<HeapRegion>() == BLOCK_ALIGN);
+
+// heap_debugのとき、確保したメモリの直前に置く
+// redzoneが確保したメモリのすぐ前に来るように、最後に置く
+#[repr(C)]
+struct DebugHeader {
+    size: usize,
+    // スラブや空きブロックから確保した先頭から、呼び出し元に返したアドレスまでの距離
+    offset: usize,
+    prev: *mut DebugHeader,
+    next: *mut DebugHeader,
+    site: AllocationSite,
+    redzone: [u8; 32],
+}
+
+const _: () = assert!(size_of::<DebugHeader>() == 128);
+
+// 確保した関数を探すために記録しておく戻りアドレス
+#[derive(Clone, Copy)]
+struct AllocationSite([u64; SITE_FRAMES]);
+
+// これらで始まる関数はアロケータの中なので、確保した場所としては表示しない
+const ALLOCATOR_FRAMES: [&str; 5] = [
+    "alloc::",
+    "core::",
+    "__rust",
+    "wasabi::heap::",
+    "wasabi::efi_allocator::",
+];
+
+impl fmt::Display for AllocationSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addresses = self.0.iter().copied().filter(|address| *address != 0);
+        for address in addresses.clone() {
+            // 戻りアドレスの1つ前で探す(backtrace.rsと同じ)
+            if let Some((name, offset)) = lookup(address - 1) {
+                if !ALLOCATOR_FRAMES.iter().any(|prefix| name.starts_with(prefix)) {
+                    return write!(f, "{name}+{:#x}", offset + 1);
+                }
+            }
+        }
+        // シンボル表がなければ、アドレスをそのまま並べる
+        for (i, address) in addresses.enumerate() {
+            if i != 0 {
+                f.write_str(" <- ")?;
+            }
+            write!(f, "{address:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+// ヒープが壊れていることを見つけた。ロックを離してからパニックする
+#[cfg_attr(not(feature = "heap_debug"), allow(dead_code))]
+struct HeapError {
+    message: &'static str,
+    address: usize,
+    site: Option<AllocationSite>,
+}
+
+impl HeapError {
+    fn report(&self) -> ! {
+        match &self.site {
+            Some(site) => panic!("{} at {:#x} (allocated at {site})", self.message, self.address),
+            None => panic!("{} at {:#x}", self.message, self.address),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    // もらった領域の合計(バイト)
+    pub heap_size: usize,
+    // 確保されているブロックの合計(バイト)
+    pub allocated: usize,
+    pub peak_allocated: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub failures: u64,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB used of {} KiB (peak {} KiB), {} live allocations, {} failures",
+            self.allocated / 1024,
+            self.heap_size / 1024,
+            self.peak_allocated / 1024,
+            self.allocations - self.frees,
+            self.failures
+        )
+    }
+}
+
+// 1つの大きさのスラブのキャッシュ
+#[derive(Clone, Copy)]
+struct SizeClass {
+    free: *mut FreeObject,
+    slabs: u64,
+    in_use: u64,
+}
+
+impl SizeClass {
+    const EMPTY: SizeClass = SizeClass {
+        free: null_mut(),
+        slabs: 0,
+        in_use: 0,
+    };
+}
+
+struct Heap {
+    free_list: *mut FreeBlock,
+    regions: *mut HeapRegion,
+    classes: [SizeClass; SIZE_CLASSES.len()],
+    // heap_debugのとき、確保されているもの(新しい順)
+    live: *mut DebugHeader,
+    stats: HeapStats,
+}
+
+// 生ポインタを持っているが、ロックの中からしか触らない
+unsafe impl Send for Heap {}
+
+// 確保するブロックの大きさ
+fn block_size(layout: &Layout) -> usize {
+    layout.size().max(1).next_multiple_of(BLOCK_ALIGN)
+}
+
+fn block_align(layout: &Layout) -> usize {
+    layout.align().max(BLOCK_ALIGN)
+}
+
+// スラブから確保できるなら、その大きさの番号を返す
+fn size_class(layout: &Layout) -> Option<usize> {
+    SIZE_CLASSES
+        .iter()
+        .position(|size| layout.size() <= *size && layout.align() <= *size)
+}
+
+// ヒープを広げるためのページをもらう
+fn allocate_pages(pages: usize) -> Option<usize> {
+    if boot_services_active() {
+        EFI_ALLOCATOR.allocate_pages(pages).map(|address| address as usize)
+    } else {
+        frame_allocator::allocate_frames(pages as u64).ok().map(|address| address as usize)
+    }
+}
+
+impl Heap {
+    const fn new() -> Heap {
+        Heap {
+            free_list: null_mut(),
+            regions: null_mut(),
+            classes: [SizeClass::EMPTY; SIZE_CLASSES.len()],
+            live: null_mut(),
+            stats: HeapStats {
+                heap_size: 0,
+                allocated: 0,
+                peak_allocated: 0,
+                allocations: 0,
+                frees: 0,
+                failures: 0,
+            },
+        }
+    }
+
+    // alignの倍数から始まるsizeバイトを、空きブロックのどこかから切り出す
+    // 前後の余りは空きブロックとして残す
+    unsafe fn take(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut link: *mut *mut FreeBlock = &mut self.free_list;
+        while let Some(block) = (*link).as_mut() {
+            let block_start = block as *mut FreeBlock as usize;
+            let block_end = block_start + block.size;
+            let start = block_start.next_multiple_of(align);
+            let end = start + size;
+            if end <= block_end {
+                let mut rest = block.next;
+                if end < block_end {
+                    let tail = end as *mut FreeBlock;
+                    tail.write(FreeBlock {
+                        size: block_end - end,
+                        next: rest,
+                    });
+                    rest = tail;
+                }
+                if start > block_start {
+                    block.size = start - block_start;
+                    block.next = rest;
+                } else {
+                    *link = rest;
+                }
+                return Some(start as *mut u8);
+            }
+            link = &mut block.next;
+        }
+        None
+    }
+
+    // [ptr, ptr + size)を空きブロックのリストにアドレス順に戻し、前後と隣り合っていればまとめる
+    unsafe fn give_back(&mut self, ptr: *mut u8, size: usize) {
+        let address = ptr as usize;
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.free_list;
+        while !next.is_null() && (next as usize) < address {
+            prev = next;
+            next = (*next).next;
+        }
+        let block = ptr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if !next.is_null() && address + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.free_list = block;
+        } else if prev as usize + (*prev).size == address {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    // sizeバイトをalignの倍数から確保できるだけ広げる
+    unsafe fn grow(&mut self, size: usize, align: usize) -> bool {
+        let bytes = (size_of::<HeapRegion>() + size + align)
+            .max(MIN_GROW_SIZE)
+            .next_multiple_of(PAGE_SIZE as usize);
+        let Some(start) = allocate_pages(bytes / PAGE_SIZE as usize) else {
+            return false;
+        };
+        let region = start as *mut HeapRegion;
+        region.write(HeapRegion {
+            size: bytes,
+            next: self.regions,
+        });
+        self.regions = region;
+        self.stats.heap_size += bytes;
+        self.give_back(region.add(1) as *mut u8, bytes - size_of::<HeapRegion>());
+        true
+    }
+
+    // 空きブロックのリストから確保する。足りなければ広げる
+    unsafe fn list_alloc(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        match self.take(size, align) {
+            Some(ptr) => Some(ptr),
+            None if self.grow(size, align) => self.take(size, align),
+            None => None,
+        }
+    }
+
+    // スラブのオブジェクトを空きに戻す
+    unsafe fn push_object(&mut self, class: usize, ptr: *mut u8) {
+        if cfg!(feature = "heap_debug") {
+            ptr.write_bytes(POISON_FREE, SIZE_CLASSES[class]);
+        }
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject {
+            next: self.classes[class].free,
+        });
+        self.classes[class].free = object;
+    }
+
+    unsafe fn slab_alloc(&mut self, class: usize) -> Result<*mut u8, HeapError> {
+        let size = SIZE_CLASSES[class];
+        if self.classes[class].free.is_null() {
+            let Some(slab) = self.list_alloc(SLAB_SIZE, SLAB_SIZE) else {
+                return Ok(null_mut());
+            };
+            // 先頭のオブジェクトから使われるように、後ろから積む
+            for i in (0..SLAB_SIZE / size).rev() {
+                self.push_object(class, slab.add(i * size));
+            }
+            self.classes[class].slabs += 1;
+        }
+        let object = self.classes[class].free;
+        self.classes[class].free = (*object).next;
+        self.classes[class].in_use += 1;
+        let ptr = object as *mut u8;
+        #[cfg(feature = "heap_debug")]
+        {
+            let rest = core::slice::from_raw_parts(
+                ptr.add(size_of::<FreeObject>()),
+                size - size_of::<FreeObject>(),
+            );
+            if rest.iter().any(|b| *b != POISON_FREE) {
+                return Err(HeapError {
+                    message: "Heap corruption: freed memory was written (use after free)",
+                    address: ptr as usize,
+                    site: None,
+                });
+            }
+        }
+        Ok(ptr)
+    }
+
+    unsafe fn slab_dealloc(&mut self, class: usize, ptr: *mut u8) {
+        self.classes[class].in_use -= 1;
+        self.push_object(class, ptr);
+    }
+
+    // スラブか空きブロックのリストから確保する。足りなければnullを返す
+    unsafe fn raw_alloc(&mut self, layout: Layout) -> Result<*mut u8, HeapError> {
+        let (ptr, size) = match size_class(&layout) {
+            Some(class) => (self.slab_alloc(class)?, SIZE_CLASSES[class]),
+            None => {
+                let size = block_size(&layout);
+                (self.list_alloc(size, block_align(&layout)).unwrap_or(null_mut()), size)
+            }
+        };
+        if ptr.is_null() {
+            return Ok(ptr);
+        }
+        self.stats.allocations += 1;
+        self.stats.allocated += size;
+        self.stats.peak_allocated = self.stats.peak_allocated.max(self.stats.allocated);
+        Ok(ptr)
+    }
+
+    unsafe fn raw_dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let size = match size_class(&layout) {
+            Some(class) => {
+                self.slab_dealloc(class, ptr);
+                SIZE_CLASSES[class]
+            }
+            None => {
+                let size = block_size(&layout);
+                if cfg!(feature = "heap_debug") {
+                    ptr.write_bytes(POISON_FREE, size);
+                }
+                self.give_back(ptr, size);
+                size
+            }
+        };
+        self.stats.frees += 1;
+        self.stats.allocated -= size;
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, HeapError> {
+        #[cfg(feature = "heap_debug")]
+        let ptr = self.debug_alloc(layout)?;
+        #[cfg(not(feature = "heap_debug"))]
+        let ptr = self.raw_alloc(layout)?;
+        if ptr.is_null() {
+            self.stats.failures += 1;
+        }
+        Ok(ptr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) -> Result<(), HeapError> {
+        #[cfg(feature = "heap_debug")]
+        self.debug_dealloc(ptr, layout)?;
+        #[cfg(not(feature = "heap_debug"))]
+        self.raw_dealloc(ptr, layout);
+        Ok(())
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        HeapRegionIterator {
+            region: self.regions,
+        }
+        .any(|(start, size)| (start..start + size).contains(&(ptr as u64)))
+    }
+}
+
+// heap_debugのとき、呼び出し元に渡すメモリの前にDebugHeaderを、後ろにレッドゾーンを置く
+#[cfg(feature = "heap_debug")]
+impl Heap {
+    // (実際に確保する大きさと境界, 先頭から呼び出し元に返すアドレスまでの距離)
+    fn debug_layout(layout: &Layout) -> (Layout, usize) {
+        let align = block_align(layout);
+        let offset = size_of::<DebugHeader>().next_multiple_of(align);
+        let raw = Layout::from_size_align(offset + layout.size() + REDZONE_SIZE, align).unwrap();
+        (raw, offset)
+    }
+
+    #[inline(never)]
+    unsafe fn debug_alloc(&mut self, layout: Layout) -> Result<*mut u8, HeapError> {
+        let (raw_layout, offset) = Self::debug_layout(&layout);
+        let raw = self.raw_alloc(raw_layout)?;
+        if raw.is_null() {
+            return Ok(raw);
+        }
+        let ptr = raw.add(offset);
+        let header = (ptr as *mut DebugHeader).sub(1);
+        let mut site = AllocationSite([0; SITE_FRAMES]);
+        for (slot, address) in site.0.iter_mut().zip(frames()) {
+            *slot = address;
+        }
+        header.write(DebugHeader {
+            size: layout.size(),
+            offset,
+            prev: null_mut(),
+            next: self.live,
+            site,
+            redzone: [REDZONE_BYTE; 32],
+        });
+        if let Some(next) = self.live.as_mut() {
+            next.prev = header;
+        }
+        self.live = header;
+        ptr.add(layout.size()).write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+        Ok(ptr)
+    }
+
+    unsafe fn debug_dealloc(&mut self, ptr: *mut u8, layout: Layout) -> Result<(), HeapError> {
+        let header = &mut *(ptr as *mut DebugHeader).sub(1);
+        let error = |message, site| HeapError {
+            message,
+            address: ptr as usize,
+            site,
+        };
+        // 解放済みなら毒で埋まっている
+        if header.redzone.iter().all(|b| *b == POISON_FREE) {
+            return Err(error("Double free or free of an invalid pointer", None));
+        }
+        // ヘッダが壊れているので、確保した場所も信用できない
+        if header.redzone.iter().any(|b| *b != REDZONE_BYTE) || header.size != layout.size() {
+            return Err(error("Heap corruption: memory before the allocation was overwritten", None));
+        }
+        let tail = core::slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+        if tail.iter().any(|b| *b != REDZONE_BYTE) {
+            return Err(error(
+                "Heap corruption: write past the end of the allocation",
+                Some(header.site),
+            ));
+        }
+        match header.prev.as_mut() {
+            Some(prev) => prev.next = header.next,
+            None => self.live = header.next,
+        }
+        if let Some(next) = header.next.as_mut() {
+            next.prev = header.prev;
+        }
+        let (raw_layout, offset) = Self::debug_layout(&layout);
+        self.raw_dealloc(ptr.sub(offset), raw_layout);
+        Ok(())
+    }
+}
+
+// ヒープがもらった領域の(先頭の物理アドレス, 大きさ)
+pub struct HeapRegionIterator {
+    region: *const HeapRegion,
+}
+
+impl Iterator for HeapRegionIterator {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let start = self.region as u64;
+        let region = unsafe { self.region.as_ref() }?;
+        self.region = region.next;
+        Some((start, region.size as u64))
+    }
+}
+
+pub struct KernelHeap {
+    heap: Mutex<Heap>,
+}
+
+impl KernelHeap {
+    pub const fn new() -> KernelHeap {
+        KernelHeap {
+            heap: Mutex::new(Heap::new()),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.heap.lock().stats
+    }
+
+    // ptrがこのヒープから確保されたものかどうか
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        self.heap.lock().contains(ptr)
+    }
+
+    // 領域は返さないので、ロックを離した後もたどってよい
+    pub fn regions(&self) -> HeapRegionIterator {
+        HeapRegionIterator {
+            region: self.heap.lock().regions,
+        }
+    }
+
+    // heapinfoコマンド: 大きさごとのスラブの使われ方と、(heap_debugのときは)確保されたままのものを一覧する
+    // ロックを持ったまま書くので、wはメモリを確保しないものにする
+    pub fn write_info(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        let heap = self.heap.lock();
+        writeln!(w, "Kernel heap: {}", heap.stats)?;
+        for (size, class) in SIZE_CLASSES.iter().zip(&heap.classes) {
+            if class.slabs != 0 {
+                writeln!(w, "  {size:4} bytes: {:5} in use, {:3} slabs", class.in_use, class.slabs)?;
+            }
+        }
+        if !cfg!(feature = "heap_debug") {
+            return writeln!(w, "Build with --features heap_debug to list live allocations");
+        }
+        writeln!(w, "Live allocations:")?;
+        let mut header = heap.live;
+        while let Some(h) = unsafe { header.as_ref() } {
+            let ptr = unsafe { header.add(1) };
+            writeln!(w, "  {ptr:#018p} {:6} bytes at {}", h.size, h.site)?;
+            header = h.next;
+        }
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let result = self.heap.lock().alloc(layout);
+        result.unwrap_or_else(|e| e.report())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let result = self.heap.lock().dealloc(ptr, layout);
+        result.unwrap_or_else(|e| e.report())
+    }
+}
+
+#[cfg_attr(not(feature = "efi_pool_allocator"), global_allocator)]
+pub static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+// 確保に失敗したときは、ヒープの状態を残してからパニックする
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    error!("heap: {}", KERNEL_HEAP.stats());
+    panic!(
+        "Out of memory: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn kernel_heap_aligns_and_merges_free_blocks() {
+        let heap = KernelHeap::new();
+        let large = Layout::from_size_align(3000, 8).unwrap();
+        let aligned = Layout::from_size_align(2100, 4096).unwrap();
+        unsafe {
+            let a = heap.alloc(large);
+            let b = heap.alloc(aligned);
+            let c = heap.alloc(large);
+            assert!(!a.is_null() && !b.is_null() && !c.is_null());
+            assert_eq!(a as usize % BLOCK_ALIGN, 0);
+            assert_eq!(b as usize % 4096, 0);
+            assert!(heap.contains(a) && heap.contains(b) && heap.contains(c));
+            if !cfg!(feature = "heap_debug") {
+                assert_eq!(heap.stats().allocated, 3008 + 2112 + 3008);
+            }
+            heap.dealloc(b, aligned);
+            heap.dealloc(a, large);
+            heap.dealloc(c, large);
+        }
+        let stats = heap.stats();
+        assert_eq!((stats.allocated, stats.allocations, stats.frees), (0, 3, 3));
+        // すべて解放すると、領域の先頭を除いて1つの空きブロックに戻る
+        let h = heap.heap.lock();
+        let block = unsafe { &*h.free_list };
+        assert!(block.next.is_null());
+        assert_eq!(block.size, stats.heap_size - size_of::<HeapRegion>());
+    }
+
+    #[test_case]
+    fn kernel_heap_serves_small_objects_from_slabs() {
+        let heap = KernelHeap::new();
+        let small = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let a = heap.alloc(small);
+            let b = heap.alloc(small);
+            assert!(!a.is_null() && !b.is_null());
+            heap.dealloc(a, small);
+            // 解放したオブジェクトがすぐに使われる
+            assert_eq!(heap.alloc(small), a);
+            heap.dealloc(a, small);
+            heap.dealloc(b, small);
+        }
+        let h = heap.heap.lock();
+        let used: Vec<_> = h.classes.iter().filter(|c| c.slabs != 0).collect();
+        assert_eq!(used.len(), 1);
+        assert_eq!((used[0].slabs, used[0].in_use), (1, 0));
+        assert_eq!(h.stats.allocated, 0);
+    }
+
+    #[test_case]
+    fn kernel_heap_grows_for_large_allocations() {
+        let heap = KernelHeap::new();
+        let large = Layout::from_size_align(MIN_GROW_SIZE * 2, 8).unwrap();
+        unsafe {
+            let a = heap.alloc(large);
+            assert!(!a.is_null());
+            a.write_bytes(0xab, large.size());
+            heap.dealloc(a, large);
+        }
+        assert!(heap.stats().heap_size > MIN_GROW_SIZE * 2);
+        assert_eq!(heap.regions().count(), 1);
+    }
+
+    #[test_case]
+    fn size_classes_respect_alignment() {
+        assert_eq!(size_class(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+        assert_eq!(size_class(&Layout::from_size_align(16, 64).unwrap()), Some(2));
+        assert_eq!(size_class(&Layout::from_size_align(2048, 8).unwrap()), Some(7));
+        assert_eq!(size_class(&Layout::from_size_align(2049, 8).unwrap()), None);
+    }
+}
@@ -0,0 +1,216 @@
+// 例外を受け取るためのGDT, TSS, IDT
+// ページフォルト(#PF)とダブルフォルト(#DF)をパニックとして報告する
+// スタックがあふれてガードページに触れると#PFを積めずに#DFになるので、#DFはIST(別のスタック)で受ける
+// 割り込みはまだ使わないので、ほかのベクタは空のまま(空のベクタに来たときも#DFになる)
+
+use core::arch::global_asm;
+use core::fmt;
+use core::mem::offset_of;
+use core::mem::size_of;
+
+use crate::mutex::Mutex;
+use crate::x86::cli;
+use crate::x86::load_gdt;
+use crate::x86::load_idt;
+use crate::x86::load_tss;
+use crate::x86::read_cr2;
+
+// GDTのエントリ。TSSのディスクリプタだけは2つ分(16バイト)を使う
+const KERNEL_CODE_SEGMENT: u64 = 0x00af_9a00_0000_ffff; // 64ビット, 実行可能, DPL 0
+const KERNEL_DATA_SEGMENT: u64 = 0x00cf_9200_0000_ffff; // 書き込み可能, DPL 0
+const KERNEL_CODE_SELECTOR: u16 = 0x08;
+const KERNEL_DATA_SELECTOR: u16 = 0x10;
+const TSS_SELECTOR: u16 = 0x18;
+const GDT_ENTRIES: usize = 5;
+
+// #DFで使うISTの番号(1から7)
+const DOUBLE_FAULT_IST: u8 = 1;
+
+const VECTOR_DOUBLE_FAULT: usize = 8;
+const VECTOR_PAGE_FAULT: usize = 14;
+
+// 64ビットモードのTSS。カーネルだけで動いているので、使うのはISTだけ
+#[repr(C, packed)]
+struct TaskStateSegment {
+    _reserved0: u32,
+    rsp: [u64; 3],
+    _reserved1: u64,
+    ist: [u64; 7],
+    _reserved2: u64,
+    _reserved3: u16,
+    io_map_base: u16,
+}
+
+const _: () = assert!(offset_of!(TaskStateSegment, rsp) == 4);
+const _: () = assert!(offset_of!(TaskStateSegment, ist) == 36);
+const _: () = assert!(offset_of!(TaskStateSegment, io_map_base) == 102);
+const _: () = assert!(size_of::<TaskStateSegment>() == 104);
+
+// IDTのエントリ(割り込みゲート)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GateDescriptor {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    attributes: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+
+const _: () = assert!(offset_of!(GateDescriptor, attributes) == 5);
+const _: () = assert!(offset_of!(GateDescriptor, offset_high) == 8);
+const _: () = assert!(size_of::<GateDescriptor>() == 16);
+
+impl GateDescriptor {
+    const EMPTY: GateDescriptor = GateDescriptor {
+        offset_low: 0,
+        selector: 0,
+        ist: 0,
+        attributes: 0,
+        offset_mid: 0,
+        offset_high: 0,
+        _reserved: 0,
+    };
+    // 存在する, DPL 0, 64ビットの割り込みゲート(入るときにIFを落とす)
+    const INTERRUPT_GATE: u8 = 0x8e;
+
+    fn interrupt_gate(handler: u64, ist: u8) -> GateDescriptor {
+        GateDescriptor {
+            offset_low: handler as u16,
+            selector: KERNEL_CODE_SELECTOR,
+            ist,
+            attributes: Self::INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+}
+
+// TSSのディスクリプタ(GDTの2エントリ分)
+fn tss_descriptor(base: u64) -> [u64; 2] {
+    let limit = size_of::<TaskStateSegment>() as u64 - 1;
+    // 存在する, DPL 0, 使用中でない64ビットTSS
+    let low = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | 0x89 << 40
+        | (limit >> 16 & 0xf) << 48
+        | (base >> 24 & 0xff) << 56;
+    [low, base >> 32]
+}
+
+// CPUが直接読むので、initの後もここから動かさない
+struct DescriptorTables {
+    gdt: [u64; GDT_ENTRIES],
+    tss: TaskStateSegment,
+    idt: [GateDescriptor; 256],
+}
+
+static DESCRIPTOR_TABLES: Mutex<DescriptorTables> = Mutex::new(DescriptorTables {
+    gdt: [0, KERNEL_CODE_SEGMENT, KERNEL_DATA_SEGMENT, 0, 0],
+    tss: TaskStateSegment {
+        _reserved0: 0,
+        rsp: [0; 3],
+        _reserved1: 0,
+        ist: [0; 7],
+        _reserved2: 0,
+        _reserved3: 0,
+        // I/O許可ビットマップは使わない(TSSの外を指す)
+        io_map_base: size_of::<TaskStateSegment>() as u16,
+    },
+    idt: [GateDescriptor::EMPTY; 256],
+});
+
+// CPUが例外のときに積むもの(エラーコードの上)
+#[repr(C)]
+struct InterruptStackFrame {
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl fmt::Debug for InterruptStackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RIP {:#018x} RSP {:#018x} RFLAGS {:#x}", self.rip, self.rsp, self.rflags)
+    }
+}
+
+// 例外の入り口。CPUが積んだエラーコードを取り出し、RIPを戻りアドレスに見立てたフレームを作って
+// Rustのハンドラを呼ぶ。バックトレースは、例外を起こした場所からたどれる
+// (エラーコードを取り出すとRSPは16の倍数からずれ、RBPを積むとまた揃う)
+macro_rules! exception_entry {
+    ($entry:ident, $handler:ident) => {
+        global_asm!(
+            ".text",
+            concat!(".global ", stringify!($entry)),
+            concat!(stringify!($entry), ":"),
+            "pop rsi",
+            "push rbp",
+            "mov rbp, rsp",
+            "lea rdi, [rsp + 8]",
+            "call {handler}",
+            "ud2",
+            handler = sym $handler,
+        );
+        extern "sysv64" {
+            fn $entry();
+        }
+    };
+}
+
+exception_entry!(wasabi_double_fault_entry, double_fault);
+exception_entry!(wasabi_page_fault_entry, page_fault);
+
+extern "sysv64" fn double_fault(frame: &InterruptStackFrame, _error_code: u64) -> ! {
+    panic!("Double fault: {frame:?}");
+}
+
+extern "sysv64" fn page_fault(frame: &InterruptStackFrame, error_code: u64) -> ! {
+    panic!("Page fault at {:#x} (error {error_code:#x}): {frame:?}", read_cr2());
+}
+
+// GDT, TSS, IDTを読み込む。double_fault_stackは#DFで使うスタックの一番上
+pub fn init(double_fault_stack: u64) {
+    // ファームウェアが割り込みを有効にしたままかもしれないので、空のベクタに来ないように止めておく
+    cli();
+    let mut tables = DESCRIPTOR_TABLES.lock();
+    tables.tss.ist[DOUBLE_FAULT_IST as usize - 1] = double_fault_stack;
+    let tss = &tables.tss as *const TaskStateSegment as u64;
+    let tss_index = TSS_SELECTOR as usize / 8;
+    tables.gdt[tss_index..tss_index + 2].copy_from_slice(&tss_descriptor(tss));
+    tables.idt[VECTOR_DOUBLE_FAULT] =
+        GateDescriptor::interrupt_gate(wasabi_double_fault_entry as usize as u64, DOUBLE_FAULT_IST);
+    tables.idt[VECTOR_PAGE_FAULT] = GateDescriptor::interrupt_gate(wasabi_page_fault_entry as usize as u64, 0);
+    unsafe {
+        load_gdt(
+            tables.gdt.as_ptr() as u64,
+            size_of::<[u64; GDT_ENTRIES]>() as u64,
+            KERNEL_CODE_SELECTOR,
+            KERNEL_DATA_SELECTOR,
+        );
+        load_tss(TSS_SELECTOR);
+        load_idt(tables.idt.as_ptr() as u64, size_of::<[GateDescriptor; 256]>() as u64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn builds_descriptors() {
+        let handler = 0x1234_5678_9abc_def0;
+        let gate = GateDescriptor::interrupt_gate(handler, DOUBLE_FAULT_IST);
+        assert_eq!((gate.offset_low, gate.offset_mid, gate.offset_high), (0xdef0, 0x9abc, 0x1234_5678));
+        assert_eq!(gate.selector, KERNEL_CODE_SELECTOR);
+        assert_eq!(gate.ist, 1);
+        let [low, high] = tss_descriptor(0xffff_8000_1234_5678);
+        assert_eq!(high, 0xffff_8000);
+        // limit 103, base[0..24], type 0x89, base[24..32]
+        assert_eq!(low, 0x1200_8934_5678_0067);
+    }
+}
@@ -0,0 +1,48 @@
+// UEFIのLoaded Image Protocol
+// このプログラム自身がどこから、どこに読み込まれたかを教えてくれる
+
+use core::mem::offset_of;
+
+use crate::EfiBootServiceTable;
+use crate::EfiGuid;
+use crate::EfiHandle;
+use crate::EfiMemoryType;
+use crate::EfiResult;
+use crate::EfiVoid;
+
+pub const EFI_LOADED_IMAGE_PROTOCOL_GUID: EfiGuid = EfiGuid {
+    data0: 0x5b1b31a1,
+    data1: 0x9562,
+    data2: 0x11d2,
+    data3: [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+#[repr(C)]
+pub struct EfiLoadedImageProtocol {
+    pub revision: u32,
+    _reserved0: [u64; 2], // ParentHandle, SystemTable
+    // このイメージを読み込んだデバイス(ESPのボリューム)
+    pub device_handle: EfiHandle,
+    _reserved1: [u64; 4], // FilePath, Reserved, LoadOptionsSize, LoadOptions
+    // メモリ上のどこに読み込まれたか
+    pub image_base: *const EfiVoid,
+    pub image_size: u64,
+    pub image_code_type: EfiMemoryType,
+    pub image_data_type: EfiMemoryType,
+    _reserved2: [u64; 1], // Unload
+}
+
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, device_handle) == 24);
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, image_base) == 64);
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, image_size) == 72);
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, image_code_type) == 80);
+
+// image_handleのLoaded Image Protocolを取得する
+pub fn loaded_image(
+    boot_services: &EfiBootServiceTable,
+    image_handle: EfiHandle,
+) -> EfiResult<&'static EfiLoadedImageProtocol> {
+    let interface =
+        boot_services.handle_protocol(image_handle, &EFI_LOADED_IMAGE_PROTOCOL_GUID)?;
+    Ok(unsafe { &*(interface as *const EfiLoadedImageProtocol) })
+}
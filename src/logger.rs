@@ -0,0 +1,360 @@
+// カーネルのログ
+// error!からtrace!までのマクロで、時刻とモジュール名の付いたログを出す
+// 出力先(シリアル、画面、ConOut)は複数選べて、すべてのログはdmesg用のリングバッファにも残る
+// ExitBootServicesの後にも使うので、ここではメモリを確保しない
+
+use core::fmt;
+use core::fmt::Write;
+
+use crate::boot_services_active;
+use crate::con_out::ConOutTextWriter;
+use crate::con_out::EfiSimpleTextOutputProtocol;
+use crate::mutex::Mutex;
+use crate::serial::com1;
+use crate::time::uptime;
+use crate::EfiSystemTable;
+use crate::VramTextWriter;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[allow(dead_code)]
+pub enum LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN ",
+            LogLevel::Info => "INFO ",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace]
+            .into_iter()
+            .find(|level| level.name().trim().eq_ignore_ascii_case(name))
+    }
+}
+
+// ログの出力先
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[repr(transparent)]
+pub struct LogSinks(pub u8);
+
+impl LogSinks {
+    pub const SERIAL: u8 = 0x1;
+    pub const FRAMEBUFFER: u8 = 0x2;
+    // Boot Servicesが終了した後は、指定していても使わない
+    pub const CON_OUT: u8 = 0x4;
+
+    fn contains(self, sink: u8) -> bool {
+        self.0 & sink != 0
+    }
+}
+
+// モジュールごとにログの細かさを変えられる数
+const MAX_MODULE_FILTERS: usize = 8;
+// dmesgのために残しておくログの大きさ(古いものから消える)
+const DMESG_BUFFER_SIZE: usize = 16 * 1024;
+
+struct Logger {
+    max_level: LogLevel,
+    // (モジュールパスの先頭, そのモジュールの細かさ)
+    module_levels: [Option<(&'static str, LogLevel)>; MAX_MODULE_FILTERS],
+    sinks: LogSinks,
+    con_out: *const EfiSimpleTextOutputProtocol,
+    framebuffer: Option<VramTextWriter>,
+}
+
+// 生ポインタを持っているが、CPUは1つだけで、ロックの中からしか触らない
+unsafe impl Send for Logger {}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    max_level: LogLevel::Info,
+    module_levels: [None; MAX_MODULE_FILTERS],
+    sinks: LogSinks(LogSinks::SERIAL),
+    con_out: core::ptr::null(),
+    framebuffer: None,
+});
+
+static DMESG: Mutex<RingBuffer<DMESG_BUFFER_SIZE>> = Mutex::new(RingBuffer::new());
+
+impl Logger {
+    fn level_for(&self, module: &str) -> LogLevel {
+        self.module_levels
+            .iter()
+            .flatten()
+            .find(|(prefix, _)| module.starts_with(prefix))
+            .map_or(self.max_level, |(_, level)| *level)
+    }
+
+    // 選ばれている出力先すべてに書く
+    fn write_fmt_to_sinks(&mut self, args: fmt::Arguments) {
+        if self.sinks.contains(LogSinks::SERIAL) {
+            let _ = com1().write_fmt(args);
+        }
+        if self.sinks.contains(LogSinks::FRAMEBUFFER) {
+            if let Some(framebuffer) = &mut self.framebuffer {
+                let _ = framebuffer.write_fmt(args);
+            }
+        }
+        if self.sinks.contains(LogSinks::CON_OUT) && boot_services_active() {
+            if let Some(con_out) = unsafe { self.con_out.as_ref() } {
+                let _ = ConOutTextWriter::new(con_out).write_fmt(args);
+            }
+        }
+    }
+}
+
+// ConOutへの出力ができるようにする。最初はシリアルとConOutに出す
+pub fn init(efi_system_table: &EfiSystemTable) {
+    let mut logger = LOGGER.lock();
+    logger.con_out = efi_system_table.con_out;
+    logger.sinks = LogSinks(LogSinks::SERIAL | LogSinks::CON_OUT);
+}
+
+// 画面への出力を始める。ConOutも同じ画面に描くので、以降はConOutには出さない
+pub fn set_framebuffer(writer: VramTextWriter) {
+    let mut logger = LOGGER.lock();
+    logger.framebuffer = Some(writer);
+    logger.sinks = LogSinks(LogSinks::SERIAL | LogSinks::FRAMEBUFFER);
+}
+
+#[allow(dead_code)]
+pub fn set_sinks(sinks: LogSinks) {
+    LOGGER.lock().sinks = sinks;
+}
+
+pub fn set_max_level(level: LogLevel) {
+    LOGGER.lock().max_level = level;
+}
+
+// module_prefixで始まるモジュールのログの細かさを変える(後から同じものを指定すると上書きする)
+pub fn set_module_level(module_prefix: &'static str, level: LogLevel) -> Result<(), &'static str> {
+    let mut logger = LOGGER.lock();
+    let slot = logger
+        .module_levels
+        .iter()
+        .position(|e| matches!(e, Some((prefix, _)) if *prefix == module_prefix))
+        .or_else(|| logger.module_levels.iter().position(|e| e.is_none()))
+        .ok_or("Too many module log filters")?;
+    logger.module_levels[slot] = Some((module_prefix, level));
+    Ok(())
+}
+
+// set_module_levelに渡すキーを探す
+// すでにmodule_prefixのフィルタがあればそのキーを、なければ空きがあるときだけNoneを返す
+pub fn module_filter_key(module_prefix: &str) -> Result<Option<&'static str>, &'static str> {
+    let logger = LOGGER.lock();
+    if let Some((prefix, _)) = logger
+        .module_levels
+        .iter()
+        .flatten()
+        .find(|(prefix, _)| *prefix == module_prefix)
+    {
+        return Ok(Some(prefix));
+    }
+    if logger.module_levels.iter().any(|e| e.is_none()) {
+        Ok(None)
+    } else {
+        Err("Too many module log filters")
+    }
+}
+
+pub fn enabled(level: LogLevel, module: &str) -> bool {
+    level <= LOGGER.lock().level_for(module)
+}
+
+// マクロから呼ばれる
+pub fn log(level: LogLevel, module: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let uptime = uptime();
+    let _ = writeln!(DMESG.lock(), "[{uptime}] {} {module}: {args}", level.name());
+    LOGGER
+        .lock()
+        .write_fmt_to_sinks(format_args!("[{uptime}] {} {module}: {args}\n", level.name()));
+}
+
+// ログではない出力(コマンドの結果や入力のエコーなど)を、ログと同じ出力先に書くためのWriter
+// dmesgには残らない
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        LOGGER.lock().write_fmt_to_sinks(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+// パニックの報告に使うWriter。選んでいる出力先によらず、使えるところすべてに書く
+// ロックが取れない(ログを書いている途中でパニックした)ときは、シリアルにだけ書く
+pub struct EmergencyConsole;
+
+impl fmt::Write for EmergencyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(mut dmesg) = DMESG.try_lock() {
+            dmesg.push(s.as_bytes());
+        }
+        match LOGGER.try_lock() {
+            Some(mut logger) => {
+                let sinks = logger.sinks;
+                logger.sinks = LogSinks(LogSinks::SERIAL | LogSinks::FRAMEBUFFER | LogSinks::CON_OUT);
+                logger.write_fmt_to_sinks(format_args!("{s}"));
+                logger.sinks = sinks;
+            }
+            None => {
+                let _ = com1().write_str(s);
+            }
+        }
+        Ok(())
+    }
+}
+
+// dmesgコマンド: 残っているログをすべて書き出す
+pub fn dmesg(w: &mut impl fmt::Write) -> fmt::Result {
+    let dmesg = DMESG.lock();
+    let (first, second) = dmesg.as_slices();
+    for part in [first, second] {
+        write_lossy(w, part)?;
+    }
+    Ok(())
+}
+
+// UTF-8として読めないところ(リングバッファの境目で切れた文字など)は'?'にする
+fn write_lossy(w: &mut impl fmt::Write, mut bytes: &[u8]) -> fmt::Result {
+    loop {
+        match core::str::from_utf8(bytes) {
+            Ok(s) => return w.write_str(s),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                w.write_str(unsafe { core::str::from_utf8_unchecked(valid) })?;
+                w.write_char('?')?;
+                bytes = &rest[e.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+}
+
+// 固定長のリングバッファ。いっぱいになったら古いものから上書きする
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    // 次に書き込む位置
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.buf[self.head] = *b;
+            self.head = (self.head + 1) % N;
+            self.len = (self.len + 1).min(N);
+        }
+    }
+
+    // 古い順に、2つに分かれた中身を返す
+    // 上書きされて途中から始まる行は、最初の改行まで読み飛ばす
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let start = (self.head + N - self.len) % N;
+        let (first, second) = if start + self.len <= N {
+            (&self.buf[start..start + self.len], &self.buf[..0])
+        } else {
+            (&self.buf[start..], &self.buf[..self.head])
+        };
+        if self.len < N {
+            return (first, second);
+        }
+        match first.iter().position(|b| *b == b'\n') {
+            Some(i) => (&first[i + 1..], second),
+            None => {
+                let i = second.iter().position(|b| *b == b'\n').map_or(second.len(), |i| i + 1);
+                (&first[..0], &second[i..])
+            }
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for RingBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::logger::log($level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log!($crate::logger::LogLevel::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log!($crate::logger::LogLevel::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log!($crate::logger::LogLevel::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log!($crate::logger::LogLevel::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log!($crate::logger::LogLevel::Trace, $($arg)*) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn ring_buffer_keeps_everything_until_full() {
+        let mut ring = RingBuffer::<16>::new();
+        ring.push(b"one\ntwo\n");
+        assert_eq!(ring.as_slices(), (&b"one\ntwo\n"[..], &b""[..]));
+    }
+
+    #[test_case]
+    fn ring_buffer_drops_partial_oldest_line() {
+        let mut ring = RingBuffer::<16>::new();
+        ring.push(b"first\nsecond\nthird\n");
+        // "first\nsecond\nthird\n"の先頭3バイトが上書きされ、"st\n"は途中からなので読み飛ばす
+        let (a, b) = ring.as_slices();
+        assert_eq!([a, b].concat(), b"second\nthird\n");
+    }
+
+    #[test_case]
+    fn log_level_from_name() {
+        assert_eq!(LogLevel::from_name("warn"), Some(LogLevel::Warn));
+        assert_eq!(LogLevel::from_name("TRACE"), Some(LogLevel::Trace));
+        assert_eq!(LogLevel::from_name("verbose"), None);
+        assert!(LogLevel::Error < LogLevel::Debug);
+    }
+}
@@ -1,103 +1,563 @@
 #![no_std]
 #![no_main]
 #![feature(offset_of)]
+#![feature(custom_test_frameworks)]
+#![feature(panic_info_message)]
+#![feature(alloc_error_handler)]
+#![test_runner(crate::test_runner::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::arch::asm;
+use core::cmp::max;
 use core::cmp::min;
 use core::fmt;
 use core::fmt::Write;
 use core::mem::offset_of;
 use core::mem::size_of;
-use core::panic::PanicInfo;
 use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+#[macro_use]
+mod logger;
+
+mod acpi;
+mod backtrace;
+mod con_in;
+mod config_table;
+mod con_out;
+mod efi_allocator;
+mod file;
+mod frame_allocator;
+mod gop;
+mod heap;
+mod interrupt;
+mod loaded_image;
+mod memory_map;
+mod mutex;
+mod paging;
+mod panic;
+mod pe;
+mod power;
+mod runtime;
+mod serial;
+mod symbols;
+mod time;
+#[cfg(test)]
+mod test_runner;
+mod x86;
+
+use acpi::Acpi;
+use con_in::EfiInputKey;
+use con_in::EfiSimpleTextInputProtocol;
+use con_out::ConOutTextWriter;
+use config_table::EfiConfigurationTable;
+use config_table::SystemTables;
+use con_out::EfiSimpleTextOutputProtocol;
+use con_out::EfiTextAttribute;
+use efi_allocator::EFI_ALLOCATOR;
+use file::open_boot_volume;
+use gop::switch_vram_mode;
+use gop::EfiGraphicsOutputBltPixel;
+use gop::EfiGraphicsPixelFormat;
+use gop::EfiPixelBitmask;
+use gop::PixelLayout;
+use heap::KERNEL_HEAP;
+use loaded_image::loaded_image;
+use logger::Console;
+use logger::LogLevel;
+use power::QemuExitCode;
+use runtime::runtime_services;
+use serial::com1;
+use runtime::EfiRuntimeServicesTable;
+use runtime::EfiVariableAttributes;
+use runtime::EFI_GLOBAL_VARIABLE_GUID;
+use runtime::WASABI_VARIABLE_GUID;
+use x86::switch_stack;
 
 type EfiVoid = u8;
 type EfiHandle = u64;
+type EfiEvent = u64;
 type Result<T> = core::result::Result<T, &'static str>;
 
+// ExitBootServicesが成功するとfalseになる
+// ファームウェアの機能を、後から呼ばれうる場所(描画など)で使う前に確認する
+static BOOT_SERVICES_ACTIVE: AtomicBool = AtomicBool::new(true);
+
+fn boot_services_active() -> bool {
+    BOOT_SERVICES_ACTIVE.load(Ordering::SeqCst)
+}
+
+// 起動時に切り替える画面の解像度(見つからなければファームウェアが選んだ解像度のまま)
+const PREFERRED_RESOLUTION: (u32, u32) = (1024, 768);
+
+// COM1のボーレート(OVMFが使っているのと同じ)
+const SERIAL_BAUD_RATE: u32 = 115200;
+
 // no_mangleを指定することで、コンパイル時の名前の変更を防ぐ。
 // UEFIのエントリポイント
-// _image_handle: UEFIのイメージハンドル
+// image_handle: UEFIのイメージハンドル
 // efi_system_table: UEFIのシステムテーブルへのポインタ
 #[no_mangle]
-fn efi_main(_image_handle: EfiHandle, efi_system_table: &EfiSystemTable) -> ! {
-    
-    let mut vram: VramBufferInfo = init_vram(efi_system_table).expect("init_vram failed");
+fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) -> ! {
+    time::init();
+
+    // Boot Servicesが使える間は、VecやStringなどをそのまま使える
+    EFI_ALLOCATOR.init(efi_system_table.boot_services);
+    runtime::init(efi_system_table.runtime_services);
+    // 失敗してもシリアルへの出力が捨てられるだけなので、そのまま続ける
+    let _ = com1().init(SERIAL_BAUD_RATE);
+    logger::init(efi_system_table);
+
+    // パニックしたときのバックトレースで、戻りアドレスをイメージの中の位置で表示できるようにする
+    if let Ok(image) = loaded_image(efi_system_table.boot_services, image_handle) {
+        backtrace::set_image_range(image.image_base as u64, image.image_size);
+    }
+    let symbol_count = symbols::load(efi_system_table.boot_services, image_handle);
+
+    // ファームウェアが用意したテーブルを探す。ACPIなどハードウェアを調べるときの起点になる
+    // ログの時刻を正しく出すため、TSCの周波数はできるだけ早く測っておく
+    let system_tables = SystemTables::from_system_table(efi_system_table);
+    let acpi = system_tables.acpi_rsdp.map(Acpi::new);
+    let tsc_khz = acpi.as_ref().and_then(time::calibrate);
+
+    // cargo testのときは、テストを実行してQEMUを終了する
+    #[cfg(test)]
+    {
+        test_runner::init(image_handle, efi_system_table);
+        test_main();
+    }
+
+    // GOPを使う前でも、ファームウェアのコンソールには文字を出せる
+    if let Some(con_out) = efi_system_table.con_out() {
+        let _ = con_out.clear_screen();
+        let _ = con_out.set_attribute(EfiTextAttribute::new(EfiTextAttribute::WHITE, EfiTextAttribute::BLACK));
+    }
+    info!("wasabi: booting...");
+    match tsc_khz {
+        Some(khz) => info!("TSC: {} MHz", khz / 1000),
+        None => warn!("TSC frequency unknown"),
+    }
+    match symbol_count {
+        Ok(count) => info!("Symbols: {count} functions"),
+        Err(e) => warn!("{e}, backtraces will not show function names"),
+    }
+
+    let mut vram: VramBufferInfo = match init_vram(efi_system_table) {
+        Ok(vram) => vram,
+        Err(e) => {
+            // 画面に何も描けないので、ConOutとシリアルにエラーを出して止まる
+            if let Some(con_out) = efi_system_table.con_out() {
+                let _ = con_out.set_attribute(EfiTextAttribute::new(EfiTextAttribute::RED, EfiTextAttribute::BLACK));
+            }
+            error!("init_vram failed: {e}");
+            loop {
+                hlt();
+            }
+        }
+    };
+
+    if let Ok(new_vram) = switch_vram_mode(efi_system_table, |info| {
+        (info.horizontal_resolution, info.vertical_resolution) == PREFERRED_RESOLUTION
+            && info.pixel_format != EfiGraphicsPixelFormat::BLT_ONLY
+    }) {
+        vram = new_vram;
+    }
 
     let vw = vram.width;
     let vh = vram.height;
 
-    fill_rect(&mut vram, 0, 0, vw, vh, 0x00_00_00).expect("fill_rect failed");
-    fill_rect(&mut vram, 32, 32, 32, 32, 0x00_00_ff).expect("fill_rect failed");
-    fill_rect(&mut vram, 64, 64, 64, 64, 0x00_ff_00).expect("fill_rect failed");
-    fill_rect(&mut vram, 128, 128, 128, 128, 0xff_00_00).expect("fill_rect failed");
+    fill_rect(&mut vram, 0, 0, vw, vh, Color::from_rgb(0x00_00_00)).expect("fill_rect failed");
+    fill_rect(&mut vram, 32, 32, 32, 32, Color::from_rgb(0x00_00_ff)).expect("fill_rect failed");
+    fill_rect(&mut vram, 64, 64, 64, 64, Color::from_rgb(0x00_ff_00)).expect("fill_rect failed");
+    fill_rect(&mut vram, 128, 128, 128, 128, Color::from_rgb(0xff_00_00)).expect("fill_rect failed");
     
     for i in 0..256 {
-        let _ = draw_point(&mut vram, i, i, 0x01_01_01);
+        let _ = draw_point(&mut vram, i, i, Color::from_rgb(0x01_01_01));
     }
 
     // Gridを描画
     let grid_size: i64 = 32;
     let rect_size: i64 = grid_size * 8;
     for i in (0..=rect_size).step_by(grid_size as usize) {
-        let _ = draw_line(&mut vram, 0, i, rect_size, i, 0xff_00_00);
-        let _ = draw_line(&mut vram, i, 0, i, rect_size, 0xff_00_00);
+        let _ = draw_line(&mut vram, 0, i, rect_size, i, Color::from_rgb(0xff_00_00));
+        let _ = draw_line(&mut vram, i, 0, i, rect_size, Color::from_rgb(0xff_00_00));
     }
 
     let cx = rect_size / 2;
     let cy = rect_size / 2;
     for i in (0..=rect_size).step_by(grid_size as usize) {
-        let _ = draw_line(&mut vram, cx, cy, 0, i, 0xff_ff_00);
-        let _ = draw_line(&mut vram, cx, cy, i, 0, 0x00_ff_ff);
-        let _ = draw_line(&mut vram, cx, cy, rect_size, i, 0xff_00_ff);
-        let _ = draw_line(&mut vram, cx, cy, i, rect_size, 0xff_ff_ff);
+        let _ = draw_line(&mut vram, cx, cy, 0, i, Color::from_rgb(0xff_ff_00));
+        let _ = draw_line(&mut vram, cx, cy, i, 0, Color::from_rgb(0x00_ff_ff));
+        let _ = draw_line(&mut vram, cx, cy, rect_size, i, Color::from_rgb(0xff_00_ff));
+        let _ = draw_line(&mut vram, cx, cy, i, rect_size, Color::from_rgb(0xff_ff_ff));
     }
 
     for (i, c) in "ABCDEF".chars().enumerate() {
-        draw_font_fg(&mut vram, (i as i64) * 16 + 256, i as i64 * 16, 0xff_ff_00, c);
+        draw_font_fg(&mut vram, (i as i64) * 16 + 256, i as i64 * 16, Color::from_rgb(0xff_ff_00), c);
     }
-    draw_font_fg(&mut vram, 0, 0, 0xff_ff_ff, 'A');
+    draw_font_fg(&mut vram, 0, 0, Color::from_rgb(0xff_ff_ff), 'A');
 
-    draw_str_fg(&mut vram, 256, 256, 0xff_ff_ff, "Hello, world!");
+    draw_str_fg(&mut vram, 256, 256, Color::from_rgb(0xff_ff_ff), "Hello, world!");
 
-    let mut w = VramTextWriter::new(&mut vram); // mutは可変
+    // ここからログは画面とシリアルに出す
+    // コマンドの結果など、ログではない出力はConsoleに書く
+    logger::set_framebuffer(VramTextWriter::new(vram));
+    let mut w = Console; // mutは可変
 
     for i in 0..4 {
-        writeln!(w, "i = {}", i).unwrap();
+        debug!("i = {}", i);
+    }
+
+    if let Ok(gp) = locate_graphic_protolocol(efi_system_table) {
+        let info = gp.mode.info;
+        info!(
+            "GOP mode {}/{}: {}x{} {:?}",
+            gp.mode.mode,
+            gp.mode.max_mode,
+            info.horizontal_resolution,
+            info.vertical_resolution,
+            info.pixel_format
+        );
+        let modes: Vec<_> = gp.modes(efi_system_table.boot_services).collect();
+        let widest = modes.iter().map(|m| m.info.horizontal_resolution).max().unwrap_or(0);
+        info!("{} modes available (widest: {widest} px)", modes.len());
+    }
+
+    let configuration_tables = efi_system_table.configuration_tables();
+    info!("Configuration tables: {}", configuration_tables.len());
+    for e in configuration_tables {
+        if let Some(name) = config_table::guid_name(&e.vendor_guid) {
+            info!("  {} {name} at {:p}", e.vendor_guid, e.vendor_table);
+        }
+    }
+    if let Some(acpi) = &acpi {
+        info!("  {:?}", acpi.rsdp());
+        for table in acpi.tables() {
+            debug!("  {table:?}");
+        }
+    }
+    if let Some(smbios) = system_tables.smbios {
+        info!("  {smbios:?}");
+    }
+    if let Some(smbios3) = system_tables.smbios3 {
+        info!("  {smbios3:?}");
+    }
+    if let Some(device_tree) = system_tables.device_tree {
+        info!("  {device_tree:?}");
+    }
+    if let Some(memory_attributes) = system_tables.memory_attributes {
+        info!("  {memory_attributes:?}");
+    }
+
+    // このプログラムが置かれているボリューム(ESP)のファイルを表示する
+    // ファイルはBoot Servicesを終了する前に閉じておく
+    if let Ok(mut root) = open_boot_volume(efi_system_table.boot_services, image_handle) {
+        info!("ESP:");
+        for info in root.read_dir().flatten() {
+            info!("  {info}");
+        }
+        if let Ok(data) = root
+            .open("EFI/BOOT/BOOTX64.EFI")
+            .and_then(|mut file| file.read_to_end())
+        {
+            let signature = data.get(..2).unwrap_or_default();
+            info!("BOOTX64.EFI: {} bytes, signature {signature:x?}", data.len());
+        }
+    }
+
+    if let Some(rt) = runtime_services() {
+        if let Ok(time) = rt.get_time() {
+            info!("Now: {time}");
+        }
+        // 起動した回数を不揮発な変数に保存しておく
+        let mut count = [0u8; 4];
+        let boot_count = match rt.get_variable("WasabiBootCount", &WASABI_VARIABLE_GUID, &mut count) {
+            Ok((4, _)) => u32::from_le_bytes(count) + 1,
+            _ => 1,
+        };
+        let _ = rt.set_variable(
+            "WasabiBootCount",
+            &WASABI_VARIABLE_GUID,
+            EfiVariableAttributes(
+                EfiVariableAttributes::NON_VOLATILE
+                    | EfiVariableAttributes::BOOTSERVICE_ACCESS
+                    | EfiVariableAttributes::RUNTIME_ACCESS,
+            ),
+            &boot_count.to_le_bytes(),
+        );
+        info!("Boot count: {boot_count}");
+        if let Ok(boot_current) = rt.get_variable_vec("BootCurrent", &EFI_GLOBAL_VARIABLE_GUID) {
+            if let [lo, hi] = boot_current[..] {
+                info!("BootCurrent: Boot{:04X}", u16::from_le_bytes([lo, hi]));
+            }
+        }
+    }
+
+    // Boot Servicesが使えるうちは、ConInでキー入力を受け取れる
+    // 入力された文字を画面に表示し、Enterで1行をコマンドとして実行する。Escで電源を切る
+    // 知らないコマンド(空行を含む)なら、そのまま起動を続ける
+    if let Some(con_in) = efi_system_table.con_in() {
+        let mut line = String::new();
+        write!(w, "Command ({COMMANDS}) or Enter to continue, Esc to power off: ").unwrap();
+        while let Ok(key) = con_in.wait_key(efi_system_table.boot_services) {
+            if key.scan_code == EfiInputKey::SCAN_ESC {
+                power::shutdown(acpi.as_ref());
+            }
+            match key.char() {
+                Some('\r') => {
+                    writeln!(w).unwrap();
+                    if !run_command(&mut w, &line, acpi.as_ref()) {
+                        break;
+                    }
+                    line.clear();
+                    write!(w, "> ").unwrap();
+                }
+                // BackSpace
+                Some('\x08') => {
+                    if line.pop().is_some() {
+                        write!(w, "{ERASE_CHAR}").unwrap();
+                    }
+                }
+                Some(c) => {
+                    line.push(c);
+                    write!(w, "{c}").unwrap();
+                }
+                None => continue,
+            }
+        }
     }
 
+    // Runtime Servicesの領域をセクションごとに保護するために、Memory Attributes Tableを写しておく
+    let runtime_attributes: Vec<EfiMemoryDescriptor> =
+        system_tables.memory_attributes.map_or(Vec::new(), |t| t.entries().collect());
+
+    // ここから先はファームウェアの機能(Boot Services)は使えない
+    // 手元に残るのはメモリマップとフレームバッファだけ
     let mut memory_map = MemoryMapHolder::new();
-    let status = efi_system_table
-        .boot_services
-        .get_memory_map(&mut memory_map);
-    writeln!(w, "{status:?}").unwrap();
+    exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map)
+        .expect("exit_from_efi_boot_services failed");
+    info!("Hello, Non-UEFI world!");
+
+    // Runtime ServicesはExitBootServicesの後も使える
+    // 仮想アドレスは物理アドレスと同じにしておく
+    if let Some(rt) = runtime_services() {
+        memory_map.identity_map_runtime_regions();
+        if rt.set_virtual_address_map(&memory_map).is_ok() {
+            if let Ok(time) = rt.get_time() {
+                info!("Now (after ExitBootServices): {time}");
+            }
+        }
+    }
 
-    let mut total_memory_size: u64 = 0;
-    for e in memory_map.iter() {
-        if e.memory_type != EfiMemoryType::CONVENTIONAL_MEMORY {
-            continue;
+    let mut kernel_stack = None;
+    // 物理メモリの管理を始める。画面に描き続けるので、フレームバッファは使わないようにしておく
+    match frame_allocator::init(&memory_map) {
+        Ok(()) => {
+            // BltOnlyの画面モードにはフレームバッファがない(アドレスが0になっている)
+            let framebuffer = if vram.pixel_layout == PixelLayout::BltOnly {
+                (0, 0)
+            } else {
+                let size = vram.pixels_per_line * vram.height * vram.bytes_per_pixel();
+                (vram.buffer as u64, size as u64)
+            };
+            frame_allocator::reserve(framebuffer.0, framebuffer.1).unwrap();
+            // LOADER_*には、このプログラム自身とEFIのプールから確保したもの(Vecなど)が残っている
+            // プールをヒープとして使っている間は、Boot Servicesの領域だけを取り込む
+            let reclaimable: &[EfiMemoryType] = if cfg!(feature = "efi_pool_allocator") {
+                &[EfiMemoryType::BOOT_SERVICE_CODE, EfiMemoryType::BOOT_SERVICE_DATA]
+            } else {
+                &[
+                    EfiMemoryType::BOOT_SERVICE_CODE,
+                    EfiMemoryType::BOOT_SERVICE_DATA,
+                    EfiMemoryType::LOADER_CODE,
+                    EfiMemoryType::LOADER_DATA,
+                ]
+            };
+            // ヒープがBoot Servicesから広げた領域も使い続ける
+            let keep = [
+                backtrace::image_range(),
+                (memory_map.memory_map_buffer as u64, memory_map.memory_map_buffer_size as u64),
+            ]
+            .into_iter()
+            .chain(KERNEL_HEAP.regions());
+            match frame_allocator::reclaim(&memory_map, reclaimable, keep) {
+                Ok(pages) => info!("Reclaimed {} KiB of boot services memory", pages * 4),
+                Err(e) => warn!("{e}"),
+            }
+            if let Some(stats) = frame_allocator::stats() {
+                info!("Physical memory: {stats}");
+            }
+            // ここからはファームウェアのページテーブルをやめて、自分で作ったものを使う
+            // フレームバッファをWrite-Combiningにした効果を、切り替える前後の塗りつぶしの速さで比べる
+            let fill_before = benchmark_fill(&mut vram);
+            match paging::init(&memory_map, framebuffer, &runtime_attributes) {
+                Ok(()) => {
+                    info!("Paging: switched to the kernel page tables");
+                    // ファームウェアのスタックはどこが一番下か分からないので、自分で確保したものに移る
+                    // すぐ下がガードページなので、あふれたらページフォルトで止まる
+                    match paging::allocate_stack(KERNEL_STACK_PAGES) {
+                        Ok(top) => {
+                            info!("Kernel stack: {:#x}-{top:#x}", top - KERNEL_STACK_PAGES * 4096);
+                            kernel_stack = Some(top);
+                        }
+                        Err(e) => warn!("Failed to allocate the kernel stack: {e}"),
+                    }
+                    // ガードページに触れたときなどに、例外をパニックとして報告できるようにする
+                    // #DFは、あふれたスタックとは別のスタックで受ける
+                    match paging::allocate_stack(EXCEPTION_STACK_PAGES) {
+                        Ok(top) => {
+                            interrupt::init(top);
+                            info!("Exception handlers: loaded (#DF stack top {top:#x})");
+                        }
+                        Err(e) => warn!("Failed to allocate the exception stack: {e}"),
+                    }
+                }
+                Err(e) => error!("Failed to initialize paging: {e}"),
+            }
+            if let (Some(before), Some(after)) = (fill_before, benchmark_fill(&mut vram)) {
+                info!("Framebuffer fill: {before} MB/s -> {after} MB/s");
+            }
         }
-        total_memory_size += e.number_of_pages;
-        writeln!(
-            w,
-            "{e:?}"
-        ).unwrap();
+        Err(e) => error!("Failed to initialize the frame allocator: {e}"),
     }
-    let total_memory_size_mib = total_memory_size * 4096 / 1024 / 1024;
-    writeln!(w, "Total Memory Size: {total_memory_size_mib} MiB").unwrap();
 
+    // ここからはカーネルのスタックで動く。ファームウェアのスタックには戻らない
+    match kernel_stack {
+        Some(top) => unsafe { switch_stack(top, move || kernel_main(&memory_map, acpi.as_ref())) },
+        None => kernel_main(&memory_map, acpi.as_ref()),
+    }
+}
+
+// ExitBootServicesの後、ページテーブルとスタックを用意し終わってから動く部分
+fn kernel_main(memory_map: &MemoryMapHolder, acpi: Option<&Acpi>) -> ! {
+    let mut w = Console;
 
+    // 隣り合う同じ種類の領域はまとめて表示する
+    for region in memory_map.regions() {
+        if region.memory_type != EfiMemoryType::CONVENTIONAL_MEMORY {
+            continue;
+        }
+        debug!("{region:?}");
+    }
+    let stats = memory_map.statistics();
+    for (memory_type, pages) in stats.iter() {
+        info!("{memory_type:?}: {} KiB", pages * 4);
+    }
+    let total_memory_size_mib =
+        stats.pages(EfiMemoryType::CONVENTIONAL_MEMORY) * 4096 / 1024 / 1024;
+    let mapped_memory_size_mib = stats.total_pages() * 4096 / 1024 / 1024;
+    info!("Total Memory Size: {total_memory_size_mib} MiB (mapped: {mapped_memory_size_mib} MiB)");
+
+    // Boot Servicesがないので、ここからはシリアルから1行ずつコマンドを受け取る
+    let mut line = String::new();
+    write!(w, "> ").unwrap();
     loop {
-        // 待機
-        hlt();
+        match com1().read_byte() {
+            b'\r' | b'\n' => {
+                writeln!(w).unwrap();
+                if !line.trim().is_empty() && !run_command(&mut w, &line, acpi) {
+                    writeln!(w, "Unknown command: {}", line.trim()).unwrap();
+                }
+                line.clear();
+                write!(w, "> ").unwrap();
+            }
+            // Backspace / Delete
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    write!(w, "{ERASE_CHAR}").unwrap();
+                }
+            }
+            b if b.is_ascii_graphic() || b == b' ' => {
+                line.push(b as char);
+                write!(w, "{}", b as char).unwrap();
+            }
+            _ => {}
+        }
     }
 }
 
+// ExitBootServicesの後に使うスタックの大きさ(ページ数)
+const KERNEL_STACK_PAGES: u64 = 64;
+// #DFを報告するときに使うスタックの大きさ(パニックの表示とバックトレースが収まればよい)
+const EXCEPTION_STACK_PAGES: u64 = 16;
+
+// run_commandが受け付けるコマンド(プロンプトに表示する)
+const COMMANDS: &str = "acpi, bt, sym <address>, pagewalk <address>, mem, heapinfo, dmesg, loglevel <level> [module], shutdown, reboot, exit [code]";
+
+// 入力の最後の1文字を画面から消す。戻って空白で上書きして、また戻る
+const ERASE_CHAR: &str = "\x08 \x08";
+
+// 1行のコマンドを実行する。知らないコマンドならfalseを返す
+fn run_command(w: &mut impl fmt::Write, line: &str, acpi: Option<&Acpi>) -> bool {
+    let mut args = line.split_whitespace();
+    match (args.next(), acpi) {
+        (Some("acpi"), Some(acpi)) => acpi::dump(w, acpi).unwrap(),
+        (Some("acpi"), None) => writeln!(w, "ACPI tables not found").unwrap(),
+        (Some("bt"), _) => backtrace::write_backtrace(w).unwrap(),
+        (Some("sym"), _) => {
+            let address = args
+                .next()
+                .and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok());
+            match address.map(|address| (address, symbols::lookup(address))) {
+                Some((address, Some((name, offset)))) => writeln!(w, "{address:#x}: {name}+{offset:#x}").unwrap(),
+                Some((address, None)) => writeln!(w, "{address:#x}: no symbol").unwrap(),
+                None => writeln!(w, "usage: sym <hex address>").unwrap(),
+            }
+        }
+        (Some("pagewalk"), _) => {
+            match args
+                .next()
+                .and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+            {
+                Some(address) => paging::dump(w, address).unwrap(),
+                None => writeln!(w, "usage: pagewalk <hex address>").unwrap(),
+            }
+        }
+        (Some("mem"), _) => {
+            match frame_allocator::stats() {
+                Some(stats) => writeln!(w, "Physical memory: {stats}").unwrap(),
+                None => writeln!(w, "Frame allocator is not initialized").unwrap(),
+            }
+            writeln!(w, "Kernel heap: {}", KERNEL_HEAP.stats()).unwrap();
+        }
+        (Some("heapinfo"), _) => KERNEL_HEAP.write_info(w).unwrap(),
+        (Some("dmesg"), _) => logger::dmesg(w).unwrap(),
+        (Some("loglevel"), _) => match (args.next().and_then(LogLevel::from_name), args.next()) {
+            (Some(level), None) => logger::set_max_level(level),
+            // モジュールのパスはプログラムの中にしかない'staticな文字列と比べるので、ここで作ったものは残しておく
+            // 同じモジュールを指定し直すときは、前に残したものを使う
+            (Some(level), Some(module)) => {
+                let result = logger::module_filter_key(module).and_then(|key| {
+                    logger::set_module_level(key.unwrap_or_else(|| String::from(module).leak()), level)
+                });
+                if let Err(e) = result {
+                    writeln!(w, "{e}").unwrap();
+                }
+            }
+            _ => writeln!(w, "usage: loglevel <error|warn|info|debug|trace> [module]").unwrap(),
+        },
+        (Some("shutdown"), _) => power::shutdown(acpi),
+        (Some("reboot"), _) => power::reboot(acpi),
+        (Some("exit"), _) => match args.next().map(str::parse) {
+            Some(Ok(code)) => power::exit_qemu_with(code),
+            _ => power::exit_qemu(QemuExitCode::Success),
+        },
+        _ => return false,
+    }
+    true
+}
+
+
 fn draw_font_fg<T: Bitmap>(
     buf: &mut T,
     x: i64,
     y: i64,
-    color: u32,
+    color: Color,
     c: char) {
         
     if let Some(font) = lookup_font(c) {
@@ -118,7 +578,7 @@ fn draw_str_fg<T: Bitmap>(
     buf: &mut T,
     x: i64,
     y: i64,
-    color: u32,
+    color: Color,
     str: &str) {
         
     for (i, c) in str.chars().enumerate() {
@@ -153,29 +613,57 @@ fn lookup_font(c: char) -> Option<[[char; 8]; 16 ]> {
     None
 }
 
-unsafe fn unchecked_draw_point<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32) {
+unsafe fn unchecked_draw_point<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: Color) {
 
     // X, Y座標から、ピクセルのアドレスを計算して色を書き込む
-    *buf.unchecked_pixel_at_mut(x, y) = color;
+    buf.unchecked_write_pixel(x, y, color);
 }
 
 fn draw_point<T: Bitmap>(
     buf: &mut T,
     x: i64,
     y: i64,
-    color: u32
+    color: Color
 ) -> Result<()> {
-    *(buf.pixel_at_mut(x, y).ok_or("Out of Range")?) = color;
+    if !buf.is_in_x_range(x) || !buf.is_in_y_range(y) {
+        return Err("Out of Range");
+    }
+    unsafe {
+        unchecked_draw_point(buf, x, y, color);
+    }
     Ok(())
 }
 
+// 画面全体をFILL_BENCHMARK_ROUNDS回塗りつぶして、1秒あたりに書き込めたバイト数(MB/s)を返す
+// 画面の内容は退避しておき、測り終わったら元に戻す
+const FILL_BENCHMARK_ROUNDS: usize = 4;
+
+fn benchmark_fill(vram: &mut VramBufferInfo) -> Option<u64> {
+    if vram.pixel_layout == PixelLayout::BltOnly {
+        return None;
+    }
+    let size = (vram.pixels_per_line * vram.height * vram.bytes_per_pixel()) as usize;
+    let saved = unsafe { core::slice::from_raw_parts(vram.buffer, size) }.to_vec();
+    let (width, height) = (vram.width, vram.height);
+    let start = time::uptime().as_micros()?;
+    for round in 0..FILL_BENCHMARK_ROUNDS {
+        let color = if round % 2 == 0 { 0x00_00_00 } else { 0xff_ff_ff };
+        unsafe { vram.unchecked_fill_rect(0, 0, width, height, Color::from_rgb(color)) };
+    }
+    let elapsed = time::uptime().as_micros()? - start;
+    unsafe { core::ptr::copy_nonoverlapping(saved.as_ptr(), vram.buffer, size) };
+    let bytes = (width * height * vram.bytes_per_pixel()) as u64 * FILL_BENCHMARK_ROUNDS as u64;
+    // 1マイクロ秒あたりのバイト数は、そのままMB/sになる
+    Some(bytes / elapsed.max(1))
+}
+
 fn fill_rect<T: Bitmap>(
     buf: &mut T,
     px: i64,
     py: i64,
     w: i64,
     h: i64,
-    color: u32
+    color: Color
 ) -> Result<()> {
     if !buf.is_in_x_range(px)
         || !buf.is_in_y_range(py)
@@ -185,12 +673,8 @@ fn fill_rect<T: Bitmap>(
         return Err("Out of range");
     }
 
-    for y in py..(py + h) {
-        for x in px..(px + w) {
-            unsafe {
-                unchecked_draw_point(buf, x, y, color);
-            }
-        }
+    unsafe {
+        buf.unchecked_fill_rect(px, py, w, h, color);
     }
     Ok(())
 }
@@ -219,7 +703,7 @@ fn draw_line<T: Bitmap>(
     y0: i64,
     x1: i64,
     y1: i64,
-    color: u32
+    color: Color
 ) -> Result<()> {
     
     if !buf.is_in_x_range(x0)
@@ -256,32 +740,65 @@ fn draw_line<T: Bitmap>(
 }
 
 
-struct VramTextWriter<'a> {
-    vram: &'a mut VramBufferInfo,
+// VramBufferInfoはフレームバッファの場所を指しているだけなので、コピーして持っておける
+// (ロガーの出力先としてグローバルに置くため)
+struct VramTextWriter {
+    vram: VramBufferInfo,
     cursor_x: i64,
     cursor_y: i64,
+    // 一番下まで書いて、上に戻ったことがあるか
+    wrapped: bool,
 }
 
-impl<'a> VramTextWriter<'a> {
-    fn new(vram: &'a mut VramBufferInfo) -> Self {
+impl VramTextWriter {
+    fn new(vram: VramBufferInfo) -> Self {
         Self {
             vram,
             cursor_x: 0,
             cursor_y: 0,
+            wrapped: false,
+        }
+    }
+
+    // 次の行に移る。画面の下まで来たら一番上の行に戻り、古い行を消してから書く
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        self.cursor_y += 16;
+        if self.cursor_y + 16 > self.vram.height {
+            self.cursor_y = 0;
+            self.wrapped = true;
+        }
+        if self.wrapped {
+            let width = self.vram.width;
+            let _ = fill_rect(&mut self.vram, 0, self.cursor_y, width, 16, Color::from_rgb(0x00_00_00));
         }
     }
 }
 
-impl fmt::Write for VramTextWriter<'_> {
+impl fmt::Write for VramTextWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
 
         for c in s.chars() {
             if c == '\n' {      // 改行があったら、次の行にY座標を移動して、X座標を0に戻す
-                self.cursor_x = 0;
-                self.cursor_y += 16;
+                self.new_line();
                 continue;
             }
-            draw_font_fg(self.vram, self.cursor_x, self.cursor_y, 0xff_ff_ff, c);
+            if c == '\r' {
+                continue;
+            }
+            // BackSpaceは1文字戻って、そこを消す
+            if c == '\x08' {
+                if self.cursor_x >= 8 {
+                    self.cursor_x -= 8;
+                    let (x, y) = (self.cursor_x, self.cursor_y);
+                    let _ = fill_rect(&mut self.vram, x, y, 8, 16, Color::from_rgb(0x00_00_00));
+                }
+                continue;
+            }
+            if self.cursor_x + 8 > self.vram.width {
+                self.new_line();
+            }
+            draw_font_fg(&mut self.vram, self.cursor_x, self.cursor_y, Color::from_rgb(0xff_ff_ff), c);
             self.cursor_x += 8;
         }
         Ok(())
@@ -293,7 +810,14 @@ impl fmt::Write for VramTextWriter<'_> {
 #[repr(C)]
 struct EfiBootServiceTable {
     // Define the structure of the EFI Boot Services Table
-    reserved0: [u64; 7],
+    reserved0: [u64; 5],
+    allocate_pages: extern "win64" fn(
+        allocate_type: u32,
+        memory_type: EfiMemoryType,
+        pages: usize,
+        memory: *mut u64,
+    ) -> EfiStatus,
+    free_pages: extern "win64" fn(memory: u64, pages: usize) -> EfiStatus,
     get_memory_map: extern "win64" fn(
         memory_map_size: *mut usize,    // *mutは生ポインタ。下位レイヤーとのやりとりのために生ポインタが必要
         memory_map: *mut u8,
@@ -301,7 +825,30 @@ struct EfiBootServiceTable {
         descriptor_size: *mut usize,
         descriptor_version: *mut u32,
     ) -> EfiStatus,
-    reserved1: [u64; 32],
+    allocate_pool: extern "win64" fn(
+        pool_type: EfiMemoryType,
+        size: usize,
+        buffer: *mut *mut EfiVoid,
+    ) -> EfiStatus,
+    free_pool: extern "win64" fn(buffer: *mut EfiVoid) -> EfiStatus,
+    reserved1: [u64; 2],
+    wait_for_event: extern "win64" fn(
+        number_of_events: usize,
+        event: *const EfiEvent,
+        index: *mut usize,
+    ) -> EfiStatus,
+    reserved2: [u64; 6],
+    handle_protocol: extern "win64" fn(
+        handle: EfiHandle,
+        protocol: *const EfiGuid,
+        interface: *mut *mut EfiVoid,
+    ) -> EfiStatus,
+    reserved3: [u64; 9],
+    exit_boot_services: extern "win64" fn(
+        image_handle: EfiHandle,
+        map_key: usize,
+    ) -> EfiStatus,
+    reserved4: [u64; 10],
     locate_protocol: extern "win64" fn(
         protocol: *const EfiGuid,
         registration: *const EfiVoid,
@@ -311,14 +858,158 @@ struct EfiBootServiceTable {
 
 impl EfiBootServiceTable {
 
-    fn get_memory_map(&self, map: &mut MemoryMapHolder) -> EfiStatus {
+    // メモリマップを取得する
+    // 必要なバッファの大きさは実行してみるまで分からないので、
+    // まずは今のバッファで呼び出し、EFI_BUFFER_TOO_SMALLが返ってきたら
+    // ファームウェアが教えてくれたサイズでバッファを確保し直してもう一度呼び出す
+    fn get_memory_map(&self, map: &mut MemoryMapHolder) -> EfiResult<()> {
+        loop {
+            match self.read_memory_map(map) {
+                Err(EfiStatus::BUFFER_TOO_SMALL) => self.grow_memory_map_buffer(map)?,
+                result => return result,
+            }
+        }
+    }
+
+    // 今のバッファのままGetMemoryMapを1回だけ呼び出す
+    // ExitBootServicesに失敗した後は、これ以外のBoot Servicesを呼んではいけない
+    fn read_memory_map(&self, map: &mut MemoryMapHolder) -> EfiResult<()> {
+        // 前回の呼び出しで書き換えられたサイズを、バッファ全体の大きさに戻しておく
+        map.memory_map_size = map.memory_map_buffer_size;
         (self.get_memory_map)(
             &mut map.memory_map_size,
-            map.memory_map_buffer.as_mut_ptr(),
+            map.memory_map_buffer,
             &mut map.map_key,
             &mut map.descriptor_size,
             &mut map.descriptor_version,
         )
+        .into_result()?;
+        map.validate()
+    }
+
+    // バッファに、あとMEMORY_MAP_EXTRA_DESCRIPTORS個のエントリが入る余裕があるか
+    fn has_memory_map_slack(map: &MemoryMapHolder) -> bool {
+        let descriptor_size = max(map.descriptor_size, size_of::<EfiMemoryDescriptor>());
+        map.memory_map_buffer_size >= map.memory_map_size + descriptor_size * MEMORY_MAP_EXTRA_DESCRIPTORS
+    }
+
+    fn grow_memory_map_buffer(&self, map: &mut MemoryMapHolder) -> EfiResult<()> {
+        // バッファを確保すること自体でメモリマップのエントリが増えるので、少し余分に確保する
+        let descriptor_size = max(map.descriptor_size, size_of::<EfiMemoryDescriptor>());
+        let new_size = map.memory_map_size + descriptor_size * MEMORY_MAP_EXTRA_DESCRIPTORS;
+
+        if !map.memory_map_buffer.is_null() {
+            self.free_pool(map.memory_map_buffer)?;
+            map.memory_map_buffer = null_mut();
+            map.memory_map_buffer_size = 0;
+        }
+
+        map.memory_map_buffer = self.allocate_pool(EfiMemoryType::LOADER_DATA, new_size)?;
+        map.memory_map_buffer_size = new_size;
+        Ok(())
+    }
+
+    // eventsのどれかがシグナル状態になるまで待ち、そのイベントの番号を返す
+    fn wait_for_event(&self, events: &[EfiEvent]) -> EfiResult<usize> {
+        let mut index = 0;
+        (self.wait_for_event)(events.len(), events.as_ptr(), &mut index).into_result()?;
+        Ok(index)
+    }
+
+    // 4KiBのページ単位でメモリを確保し、その物理アドレスを返す
+    fn allocate_pages(
+        &self,
+        allocate_type: EfiAllocateType,
+        memory_type: EfiMemoryType,
+        pages: usize,
+    ) -> EfiResult<u64> {
+        let (allocate_type, mut memory) = match allocate_type {
+            EfiAllocateType::AnyPages => (0, 0),
+            EfiAllocateType::MaxAddress(max_address) => (1, max_address),
+            EfiAllocateType::Address(address) => (2, address),
+        };
+        (self.allocate_pages)(allocate_type, memory_type, pages, &mut memory).into_result()?;
+        Ok(memory)
+    }
+
+    fn free_pages(&self, memory: u64, pages: usize) -> EfiResult<()> {
+        (self.free_pages)(memory, pages).into_result()
+    }
+
+    // バイト単位でメモリを確保する。返るアドレスは8バイト境界に揃っている
+    fn allocate_pool(&self, pool_type: EfiMemoryType, size: usize) -> EfiResult<*mut EfiVoid> {
+        let mut buffer = null_mut::<EfiVoid>();
+        (self.allocate_pool)(pool_type, size, &mut buffer).into_result()?;
+        Ok(buffer)
+    }
+
+    // AllocatePoolで確保したメモリや、ファームウェアが確保したメモリ(QueryModeの結果など)を解放する
+    fn free_pool(&self, buffer: *mut EfiVoid) -> EfiResult<()> {
+        (self.free_pool)(buffer).into_result()
+    }
+
+    fn exit_boot_services(&self, image_handle: EfiHandle, map_key: usize) -> EfiResult<()> {
+        (self.exit_boot_services)(image_handle, map_key).into_result()
+    }
+
+    // handleがサポートしているプロトコルのうち、GUIDで指定したもののインターフェースを取得する
+    fn handle_protocol(&self, handle: EfiHandle, protocol: &EfiGuid) -> EfiResult<*mut EfiVoid> {
+        let mut interface = null_mut::<EfiVoid>();
+        (self.handle_protocol)(handle, protocol, &mut interface).into_result()?;
+        Ok(interface)
+    }
+
+    // GUIDで指定したプロトコルのインターフェースへのポインタを取得する
+    fn locate_protocol(&self, protocol: &EfiGuid) -> EfiResult<*mut EfiVoid> {
+        let mut interface = null_mut::<EfiVoid>();
+        (self.locate_protocol)(
+            protocol,
+            null_mut::<EfiVoid>(),
+            &mut interface, // UEFIとのやりとりをするために生ポインタを渡している
+        )
+        .into_result()?;
+        Ok(interface)
+    }
+}
+
+// AllocatePagesでどこのページを確保するか
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[allow(dead_code)]
+enum EfiAllocateType {
+    // どこでも良い
+    AnyPages,
+    // 指定したアドレス以下のどこか
+    MaxAddress(u64),
+    // 指定したアドレスちょうど
+    Address(u64),
+}
+
+// Boot Servicesを終了して、マシンの制御をファームウェアから受け取る
+// ExitBootServicesには最新のメモリマップのmap_keyを渡す必要がある。
+// メモリマップを取得してから呼び出すまでの間にファームウェアがメモリを確保すると
+// map_keyが古くなってEFI_INVALID_PARAMETERが返るので、その場合は取り直して再試行する
+// 失敗した後はGetMemoryMapしか呼べない(バッファを確保し直せない)ので、最初から余裕を持たせて確保しておく
+fn exit_from_efi_boot_services(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+    memory_map: &mut MemoryMapHolder,
+) -> EfiResult<()> {
+    let boot_services = efi_system_table.boot_services;
+    boot_services.get_memory_map(memory_map)?;
+    if !EfiBootServiceTable::has_memory_map_slack(memory_map) {
+        boot_services.grow_memory_map_buffer(memory_map)?;
+        boot_services.get_memory_map(memory_map)?;
+    }
+    loop {
+        match boot_services.exit_boot_services(image_handle, memory_map.map_key) {
+            // バッファが足りなくなっていたら、BUFFER_TOO_SMALLのまま失敗する
+            Err(EfiStatus::INVALID_PARAMETER) => boot_services.read_memory_map(memory_map)?,
+            Err(e) => return Err(e),
+            Ok(()) => {
+                BOOT_SERVICES_ACTIVE.store(false, Ordering::SeqCst);
+                return Ok(());
+            }
+        }
     }
 }
 
@@ -332,32 +1023,93 @@ struct EfiMemoryDescriptor {
     attribute: u64,
 }
 
-const MEMORY_MAP_BUFFER_SIZE: usize = 0x8000; // 32KB;
+// メモリマップのバッファを確保し直すときに、余分に確保しておくエントリの数
+const MEMORY_MAP_EXTRA_DESCRIPTORS: usize = 8;
+
+// このプログラムが解釈できるEfiMemoryDescriptorの形式
+const EFI_MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+
+// メモリの種類
+// 0x70000000以降はOEMやOSが独自に使う値なので、enumではなくu32をそのまま包んでおく
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[repr(transparent)]
+pub struct EfiMemoryType(pub u32);
+
+impl EfiMemoryType {
+    pub const RESERVED: EfiMemoryType = EfiMemoryType(0);
+    pub const LOADER_CODE: EfiMemoryType = EfiMemoryType(1);
+    pub const LOADER_DATA: EfiMemoryType = EfiMemoryType(2);
+    pub const BOOT_SERVICE_CODE: EfiMemoryType = EfiMemoryType(3);
+    pub const BOOT_SERVICE_DATA: EfiMemoryType = EfiMemoryType(4);
+    pub const RUNTIME_SERVICE_CODE: EfiMemoryType = EfiMemoryType(5);
+    pub const RUNTIME_SERVICE_DATA: EfiMemoryType = EfiMemoryType(6);
+    pub const CONVENTIONAL_MEMORY: EfiMemoryType = EfiMemoryType(7);
+    pub const UNUSABLE_MEMORY: EfiMemoryType = EfiMemoryType(8);
+    pub const ACPI_RECLAIM_MEMORY: EfiMemoryType = EfiMemoryType(9);
+    pub const ACPI_MEMORY_NVS: EfiMemoryType = EfiMemoryType(10);
+    pub const MEMORY_MAPPED_IO: EfiMemoryType = EfiMemoryType(11);
+    pub const MEMORY_MAPPED_IO_PORT_SPACE: EfiMemoryType = EfiMemoryType(12);
+    pub const PAL_CODE: EfiMemoryType = EfiMemoryType(13);
+    pub const PERSISTENT_MEMORY: EfiMemoryType = EfiMemoryType(14);
+    pub const UNACCEPTED_MEMORY: EfiMemoryType = EfiMemoryType(15);
+
+    // 仕様で定義されている種類の数(0からUNACCEPTED_MEMORYまで)
+    pub const NUM_STANDARD_TYPES: usize = 16;
+
+    const OEM_RESERVED_START: u32 = 0x7000_0000;
+    const OS_RESERVED_START: u32 = 0x8000_0000;
+
+    pub fn is_oem_reserved(self) -> bool {
+        (Self::OEM_RESERVED_START..Self::OS_RESERVED_START).contains(&self.0)
+    }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-#[repr(i64)]
-#[allow(non_camel_case_types)]
-pub enum EfiMemoryType {
-    RESERVED = 0,
-    LOADER_CODE,
-    LOADER_DATA,
-    BOOT_SERVICE_CODE,
-    BOOT_SERVICE_DATA,
-    RUNTIME_SERVICE_CODE,
-    RUNTIME_SERVICE_DATA,
-    CONVENTIONAL_MEMORY,
-    UNUSABLE_MEMORY,
-    ACPI_RECLAIM_MEMORY,
-    ACPI_MEMORY_NVS,
-    MEMORY_MAPPED_IO,
-    MEMORY_MAPPED_IO_PORT_SPACE,
-    PAL_CODE,
-    PERSISTENT_MEMORY,
+    pub fn is_os_reserved(self) -> bool {
+        self.0 >= Self::OS_RESERVED_START
+    }
+
+    pub fn name(self) -> Option<&'static str> {
+        let name = match self {
+            Self::RESERVED => "RESERVED",
+            Self::LOADER_CODE => "LOADER_CODE",
+            Self::LOADER_DATA => "LOADER_DATA",
+            Self::BOOT_SERVICE_CODE => "BOOT_SERVICE_CODE",
+            Self::BOOT_SERVICE_DATA => "BOOT_SERVICE_DATA",
+            Self::RUNTIME_SERVICE_CODE => "RUNTIME_SERVICE_CODE",
+            Self::RUNTIME_SERVICE_DATA => "RUNTIME_SERVICE_DATA",
+            Self::CONVENTIONAL_MEMORY => "CONVENTIONAL_MEMORY",
+            Self::UNUSABLE_MEMORY => "UNUSABLE_MEMORY",
+            Self::ACPI_RECLAIM_MEMORY => "ACPI_RECLAIM_MEMORY",
+            Self::ACPI_MEMORY_NVS => "ACPI_MEMORY_NVS",
+            Self::MEMORY_MAPPED_IO => "MEMORY_MAPPED_IO",
+            Self::MEMORY_MAPPED_IO_PORT_SPACE => "MEMORY_MAPPED_IO_PORT_SPACE",
+            Self::PAL_CODE => "PAL_CODE",
+            Self::PERSISTENT_MEMORY => "PERSISTENT_MEMORY",
+            Self::UNACCEPTED_MEMORY => "UNACCEPTED_MEMORY",
+            _ => return None,
+        };
+        Some(name)
+    }
+}
+
+impl fmt::Debug for EfiMemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.name() {
+            f.write_str(name)
+        } else if self.is_oem_reserved() {
+            write!(f, "OEM_RESERVED({:#x})", self.0)
+        } else if self.is_os_reserved() {
+            write!(f, "OS_RESERVED({:#x})", self.0)
+        } else {
+            write!(f, "UNKNOWN({:#x})", self.0)
+        }
+    }
 }
 
+// メモリマップのバッファはAllocatePoolで確保する(LOADER_DATAなのでExitBootServices後も残る)
 struct MemoryMapHolder {
-    memory_map_buffer: [u8; MEMORY_MAP_BUFFER_SIZE],
-    memory_map_size: usize,
+    memory_map_buffer: *mut u8,
+    memory_map_buffer_size: usize, // 確保したバッファの大きさ
+    memory_map_size: usize,        // 実際にメモリマップが書き込まれた大きさ
     map_key: usize,
     descriptor_size: usize,
     descriptor_version: u32,
@@ -372,11 +1124,11 @@ impl<'a> Iterator for MemoryMapIterator<'a> {
     type Item = &'a EfiMemoryDescriptor;
 
     fn next(&mut self) -> Option<&'a EfiMemoryDescriptor> {
-        if self.ofs >= self.map.memory_map_size {
+        if !self.map.is_valid() || self.ofs + self.map.descriptor_size > self.map.memory_map_size {
             None
         } else {
             let e: &EfiMemoryDescriptor = unsafe {
-                &*(self.map.memory_map_buffer.as_ptr().add(self.ofs) as *const EfiMemoryDescriptor)
+                &*(self.map.memory_map_buffer.add(self.ofs) as *const EfiMemoryDescriptor)
             };
             self.ofs += self.map.descriptor_size;
             Some(e)
@@ -387,14 +1139,32 @@ impl<'a> Iterator for MemoryMapIterator<'a> {
 impl MemoryMapHolder {
     pub const fn new() -> MemoryMapHolder{
         MemoryMapHolder {
-            memory_map_buffer: [0; MEMORY_MAP_BUFFER_SIZE],
-            memory_map_size: MEMORY_MAP_BUFFER_SIZE,
+            memory_map_buffer: null_mut(),
+            memory_map_buffer_size: 0,
+            memory_map_size: 0,
             map_key: 0,
             descriptor_size: 0,
             descriptor_version: 0,
         }
     }
 
+    // ファームウェアが返したエントリの形式が、EfiMemoryDescriptorとして読めるものか確認する
+    // descriptor_sizeは将来のバージョンで大きくなりうるので、小さすぎないことだけを確認する
+    fn is_valid(&self) -> bool {
+        !self.memory_map_buffer.is_null()
+            && self.descriptor_version == EFI_MEMORY_DESCRIPTOR_VERSION
+            && self.descriptor_size >= size_of::<EfiMemoryDescriptor>()
+            && self.memory_map_size <= self.memory_map_buffer_size
+    }
+
+    fn validate(&self) -> EfiResult<()> {
+        if self.is_valid() {
+            Ok(())
+        } else {
+            Err(EfiStatus::INCOMPATIBLE_VERSION)
+        }
+    }
+
     pub fn iter(&self) -> MemoryMapIterator {
         MemoryMapIterator {
             map: self,
@@ -407,17 +1177,52 @@ impl MemoryMapHolder {
 // 構造体のフィールドのオフセットを確認
 // こうすることで、コンパイル時にチェックできる
 // 例えば、新しいフィールドを前に追加したときにオフセットが意図してズレたときに気づける
+const _: () = assert!(offset_of!(EfiMemoryDescriptor, physical_start) == 8);
+const _: () = assert!(offset_of!(EfiMemoryDescriptor, attribute) == 32);
+const _: () = assert!(size_of::<EfiMemoryDescriptor>() == 40);
+const _: () = assert!(offset_of!(EfiBootServiceTable, allocate_pages) == 40);
+const _: () = assert!(offset_of!(EfiBootServiceTable, free_pages) == 48);
 const _: () = assert!(offset_of!(EfiBootServiceTable, get_memory_map) == 56);
+const _: () = assert!(offset_of!(EfiBootServiceTable, allocate_pool) == 64);
+const _: () = assert!(offset_of!(EfiBootServiceTable, free_pool) == 72);
+const _: () = assert!(offset_of!(EfiBootServiceTable, wait_for_event) == 96);
+const _: () = assert!(offset_of!(EfiBootServiceTable, handle_protocol) == 152);
+const _: () = assert!(offset_of!(EfiBootServiceTable, exit_boot_services) == 232);
 const _: () = assert!(offset_of!(EfiBootServiceTable, locate_protocol) == 320);
 
 #[repr(C)]
 struct EfiSystemTable {
     // Define the structure of the EFI System Table
-    _reserved0: [u64; 12],
+    _reserved0: [u64; 6],
+    con_in: *const EfiSimpleTextInputProtocol,
+    _reserved1: [u64; 1],
+    con_out: *const EfiSimpleTextOutputProtocol,
+    _reserved2: [u64; 2],
+    pub runtime_services: *const EfiRuntimeServicesTable,
     pub boot_services: &'static EfiBootServiceTable,
+    number_of_table_entries: usize,
+    configuration_table: *const EfiConfigurationTable,
 }
 
+const _: () = assert!(offset_of!(EfiSystemTable, con_in) == 48);
+const _: () = assert!(offset_of!(EfiSystemTable, con_out) == 64);
+const _: () = assert!(offset_of!(EfiSystemTable, runtime_services) == 88);
 const _: () = assert!(offset_of!(EfiSystemTable, boot_services) == 96);
+const _: () = assert!(offset_of!(EfiSystemTable, number_of_table_entries) == 104);
+const _: () = assert!(offset_of!(EfiSystemTable, configuration_table) == 112);
+
+impl EfiSystemTable {
+    // ファームウェアのキーボード入力。Boot Servicesが終了するまでの間だけ使える
+    fn con_in(&self) -> Option<&EfiSimpleTextInputProtocol> {
+        unsafe { self.con_in.as_ref() }
+    }
+
+    // ファームウェアのテキストコンソール。Boot Servicesが終了するまでの間だけ使える
+    fn con_out(&self) -> Option<ConOutTextWriter> {
+        // 生ポインタがnullでなければ参照に変換する
+        unsafe { self.con_out.as_ref() }.map(ConOutTextWriter::new)
+    }
+}
 
 const EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data0: 0x9042a9de,
@@ -426,6 +1231,43 @@ const EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data3: [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a],
 };
 
+// 文字列をUEFIに渡せるNUL終端のUCS-2に変換する(UCS-2で表せない文字は'?'にする)
+fn to_ucs2(s: &str) -> Vec<u16> {
+    s.chars()
+        .map(|c| u16::try_from(c as u32).unwrap_or('?' as u16))
+        .chain([0])
+        .collect()
+}
+
+// 日時(GetTimeやファイルの情報で使う)
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+struct EfiTime {
+    pub year: u16, // 1900 - 9999
+    pub month: u8, // 1 - 12
+    pub day: u8,   // 1 - 31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16, // UTCからのずれ(分)。0x07FFならローカル時刻
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+const _: () = assert!(size_of::<EfiTime>() == 16);
+
+impl fmt::Display for EfiTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 struct EfiGuid {
@@ -435,10 +1277,40 @@ struct EfiGuid {
     pub data3: [u8; 8],
 }
 
+// 8be4df61-93ca-11d2-aa0d-00e098032b8c のような、よく見る形式で表示する
+impl fmt::Display for EfiGuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.data3;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.data0, self.data1, self.data2, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct EfiGraphicsOutputProtocol<'a> {
-    reserved: [u64; 3],
+    query_mode: extern "win64" fn(
+        this: *const Self,
+        mode_number: u32,
+        size_of_info: *mut usize,
+        info: *mut *const EfiGraphicsOutputProtocolPixelInfo,
+    ) -> EfiStatus,
+    set_mode: extern "win64" fn(this: *const Self, mode_number: u32) -> EfiStatus,
+    blt: extern "win64" fn(
+        this: *const Self,
+        blt_buffer: *mut EfiGraphicsOutputBltPixel,
+        blt_operation: u32,
+        source_x: usize,
+        source_y: usize,
+        destination_x: usize,
+        destination_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> EfiStatus,
     pub mode: &'a EfiGraphicsOutputProtocolMode<'a>,
 }
 
@@ -454,47 +1326,202 @@ struct EfiGraphicsOutputProtocolMode<'a> {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct EfiGraphicsOutputProtocolPixelInfo {
     version: u32,
     pub horizontal_resolution: u32,
     pub vertical_resolution: u32,
-    pub _padding0: [u32; 5],
+    pub pixel_format: EfiGraphicsPixelFormat,
+    pub pixel_information: EfiPixelBitmask, // pixel_formatがBIT_MASKのときだけ使う
     pub pixels_per_scan_line: u32, // 水平方向に含まれる画素数
 }
 
 const _: () = assert!(size_of::<EfiGraphicsOutputProtocolPixelInfo>() == 36);
+const _: () = assert!(offset_of!(EfiGraphicsOutputProtocol, set_mode) == 8);
+const _: () = assert!(offset_of!(EfiGraphicsOutputProtocol, blt) == 16);
+const _: () = assert!(offset_of!(EfiGraphicsOutputProtocol, mode) == 24);
 
 fn locate_graphic_protolocol<'a>(
     efi_system_table: &EfiSystemTable,
 ) -> Result<&'a EfiGraphicsOutputProtocol<'a>> {
 
-    // EfiGraphicsOutputProtocolへのポインタを格納するための変数
-    let mut graphic_output_protocol = null_mut::<EfiGraphicsOutputProtocol>();
-
     // EFI_GRAPHICS_OUTPUT_PROTOCOL_GUIDはグラフィックス機能のためのプロトコルを示すGUID
-    let status = (efi_system_table.boot_services.locate_protocol)(
-        &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
-        null_mut::<EfiVoid>(),
-        &mut graphic_output_protocol as *mut *mut EfiGraphicsOutputProtocol as *mut *mut EfiVoid,   // UEFIとのやりとりをするために生ポインタにキャストしている
-    );
-
-    if status != EfiStatus::Success {
-        return Err("Failed to locate graphics output protocol");
-    }
+    let graphic_output_protocol = efi_system_table
+        .boot_services
+        .locate_protocol(&EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID)
+        .or(Err("Failed to locate graphics output protocol"))?
+        as *const EfiGraphicsOutputProtocol;
 
     // 生ポインタから参照に変換して返す
     Ok(unsafe { &*graphic_output_protocol })
 }
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+// UEFIの関数が返すステータスコード
+// ファームウェアは仕様にない値(OEM独自のコードなど)も返しうるので、
+// enumではなくu64をそのまま包んで、どんな値を受け取っても未定義動作にならないようにする
+#[derive(PartialEq, Eq, Copy, Clone)]
 #[must_use]
-#[repr(u64)]
-enum EfiStatus {
-    Success = 0,
-    // Define other EFI status codes as needed
+#[repr(transparent)]
+pub struct EfiStatus(u64);
+
+type EfiResult<T> = core::result::Result<T, EfiStatus>;
+
+impl EfiStatus {
+    // 最上位ビットが立っていればエラー、立っていなければ成功または警告
+    const ERROR_BIT: u64 = 1 << 63;
+    // 最上位から2番目のビットが立っているものはOEMが定義したコード
+    const OEM_BIT: u64 = 1 << 62;
+
+    const SUCCESS: EfiStatus = EfiStatus(0);
+
+    // 警告
+    const WARN_UNKNOWN_GLYPH: EfiStatus = EfiStatus(1);
+    const WARN_DELETE_FAILURE: EfiStatus = EfiStatus(2);
+    const WARN_WRITE_FAILURE: EfiStatus = EfiStatus(3);
+    const WARN_BUFFER_TOO_SMALL: EfiStatus = EfiStatus(4);
+    const WARN_STALE_DATA: EfiStatus = EfiStatus(5);
+    const WARN_FILE_SYSTEM: EfiStatus = EfiStatus(6);
+    const WARN_RESET_REQUIRED: EfiStatus = EfiStatus(7);
+
+    // エラー
+    const LOAD_ERROR: EfiStatus = EfiStatus::error(1);
+    const INVALID_PARAMETER: EfiStatus = EfiStatus::error(2);
+    const UNSUPPORTED: EfiStatus = EfiStatus::error(3);
+    const BAD_BUFFER_SIZE: EfiStatus = EfiStatus::error(4);
+    const BUFFER_TOO_SMALL: EfiStatus = EfiStatus::error(5);
+    const NOT_READY: EfiStatus = EfiStatus::error(6);
+    const DEVICE_ERROR: EfiStatus = EfiStatus::error(7);
+    const WRITE_PROTECTED: EfiStatus = EfiStatus::error(8);
+    const OUT_OF_RESOURCES: EfiStatus = EfiStatus::error(9);
+    const VOLUME_CORRUPTED: EfiStatus = EfiStatus::error(10);
+    const VOLUME_FULL: EfiStatus = EfiStatus::error(11);
+    const NO_MEDIA: EfiStatus = EfiStatus::error(12);
+    const MEDIA_CHANGED: EfiStatus = EfiStatus::error(13);
+    const NOT_FOUND: EfiStatus = EfiStatus::error(14);
+    const ACCESS_DENIED: EfiStatus = EfiStatus::error(15);
+    const NO_RESPONSE: EfiStatus = EfiStatus::error(16);
+    const NO_MAPPING: EfiStatus = EfiStatus::error(17);
+    const TIMEOUT: EfiStatus = EfiStatus::error(18);
+    const NOT_STARTED: EfiStatus = EfiStatus::error(19);
+    const ALREADY_STARTED: EfiStatus = EfiStatus::error(20);
+    const ABORTED: EfiStatus = EfiStatus::error(21);
+    const ICMP_ERROR: EfiStatus = EfiStatus::error(22);
+    const TFTP_ERROR: EfiStatus = EfiStatus::error(23);
+    const PROTOCOL_ERROR: EfiStatus = EfiStatus::error(24);
+    const INCOMPATIBLE_VERSION: EfiStatus = EfiStatus::error(25);
+    const SECURITY_VIOLATION: EfiStatus = EfiStatus::error(26);
+    const CRC_ERROR: EfiStatus = EfiStatus::error(27);
+    const END_OF_MEDIA: EfiStatus = EfiStatus::error(28);
+    const END_OF_FILE: EfiStatus = EfiStatus::error(31);
+    const INVALID_LANGUAGE: EfiStatus = EfiStatus::error(32);
+    const COMPROMISED_DATA: EfiStatus = EfiStatus::error(33);
+    const IP_ADDRESS_CONFLICT: EfiStatus = EfiStatus::error(34);
+    const HTTP_ERROR: EfiStatus = EfiStatus::error(35);
+
+    const fn error(code: u64) -> EfiStatus {
+        EfiStatus(Self::ERROR_BIT | code)
+    }
+
+    fn is_success(self) -> bool {
+        self == Self::SUCCESS
+    }
+
+    fn is_error(self) -> bool {
+        self.0 & Self::ERROR_BIT != 0
+    }
+
+    fn is_warning(self) -> bool {
+        !self.is_success() && !self.is_error()
+    }
+
+    fn is_oem(self) -> bool {
+        self.0 & Self::OEM_BIT != 0
+    }
+
+    // エラーかどうかを表すビットを除いたコード番号
+    fn code(self) -> u64 {
+        self.0 & !Self::ERROR_BIT
+    }
+
+    // 警告は成功扱いにして、エラーのときだけErrを返す
+    fn into_result(self) -> EfiResult<()> {
+        if self.is_error() {
+            Err(self)
+        } else {
+            Ok(())
+        }
+    }
+
+    // 仕様に定義されているコードなら、その名前を返す
+    fn name(self) -> Option<&'static str> {
+        let name = match self {
+            Self::SUCCESS => "SUCCESS",
+            Self::WARN_UNKNOWN_GLYPH => "WARN_UNKNOWN_GLYPH",
+            Self::WARN_DELETE_FAILURE => "WARN_DELETE_FAILURE",
+            Self::WARN_WRITE_FAILURE => "WARN_WRITE_FAILURE",
+            Self::WARN_BUFFER_TOO_SMALL => "WARN_BUFFER_TOO_SMALL",
+            Self::WARN_STALE_DATA => "WARN_STALE_DATA",
+            Self::WARN_FILE_SYSTEM => "WARN_FILE_SYSTEM",
+            Self::WARN_RESET_REQUIRED => "WARN_RESET_REQUIRED",
+            Self::LOAD_ERROR => "LOAD_ERROR",
+            Self::INVALID_PARAMETER => "INVALID_PARAMETER",
+            Self::UNSUPPORTED => "UNSUPPORTED",
+            Self::BAD_BUFFER_SIZE => "BAD_BUFFER_SIZE",
+            Self::BUFFER_TOO_SMALL => "BUFFER_TOO_SMALL",
+            Self::NOT_READY => "NOT_READY",
+            Self::DEVICE_ERROR => "DEVICE_ERROR",
+            Self::WRITE_PROTECTED => "WRITE_PROTECTED",
+            Self::OUT_OF_RESOURCES => "OUT_OF_RESOURCES",
+            Self::VOLUME_CORRUPTED => "VOLUME_CORRUPTED",
+            Self::VOLUME_FULL => "VOLUME_FULL",
+            Self::NO_MEDIA => "NO_MEDIA",
+            Self::MEDIA_CHANGED => "MEDIA_CHANGED",
+            Self::NOT_FOUND => "NOT_FOUND",
+            Self::ACCESS_DENIED => "ACCESS_DENIED",
+            Self::NO_RESPONSE => "NO_RESPONSE",
+            Self::NO_MAPPING => "NO_MAPPING",
+            Self::TIMEOUT => "TIMEOUT",
+            Self::NOT_STARTED => "NOT_STARTED",
+            Self::ALREADY_STARTED => "ALREADY_STARTED",
+            Self::ABORTED => "ABORTED",
+            Self::ICMP_ERROR => "ICMP_ERROR",
+            Self::TFTP_ERROR => "TFTP_ERROR",
+            Self::PROTOCOL_ERROR => "PROTOCOL_ERROR",
+            Self::INCOMPATIBLE_VERSION => "INCOMPATIBLE_VERSION",
+            Self::SECURITY_VIOLATION => "SECURITY_VIOLATION",
+            Self::CRC_ERROR => "CRC_ERROR",
+            Self::END_OF_MEDIA => "END_OF_MEDIA",
+            Self::END_OF_FILE => "END_OF_FILE",
+            Self::INVALID_LANGUAGE => "INVALID_LANGUAGE",
+            Self::COMPROMISED_DATA => "COMPROMISED_DATA",
+            Self::IP_ADDRESS_CONFLICT => "IP_ADDRESS_CONFLICT",
+            Self::HTTP_ERROR => "HTTP_ERROR",
+            _ => return None,
+        };
+        Some(name)
+    }
 }
 
+impl fmt::Debug for EfiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = self.name() {
+            return write!(f, "EFI_{name}");
+        }
+        let kind = if self.is_warning() { "WARNING" } else { "ERROR" };
+        let origin = if self.is_oem() { "OEM" } else { "UNKNOWN" };
+        write!(f, "EFI_{origin}_{kind}({:#x})", self.code())
+    }
+}
+
+// Result<T>(エラーが&'static str)を返す関数の中でも`?`でEfiStatusを扱えるようにする
+impl From<EfiStatus> for &'static str {
+    fn from(status: EfiStatus) -> Self {
+        status.name().unwrap_or("EFI_UNKNOWN_ERROR")
+    }
+}
+
+const _: () = assert!(size_of::<EfiStatus>() == 8);
+
 pub fn hlt() {
     unsafe {
         // CPUに停止させる命令
@@ -504,12 +1531,33 @@ pub fn hlt() {
 
 // use core::{panic::PanicInfo, slice};
 
-#[cfg(not(test))]
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {
-        // 待機
-        hlt();
+
+// 描画に使う色
+// フレームバッファのピクセルの形式(RGBかBGRかなど)によらない形で持っておき、書き込むときに変換する
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    // 0xRRGGBBの形式の値から作る
+    const fn from_rgb(rgb: u32) -> Color {
+        Color {
+            r: (rgb >> 16) as u8,
+            g: (rgb >> 8) as u8,
+            b: rgb as u8,
+        }
+    }
+}
+
+// ピクセルの値を、bytes_per_pixelバイト分だけフレームバッファに書き込む
+unsafe fn write_pixel_value(p: *mut u8, value: u32, bytes_per_pixel: i64) {
+    if bytes_per_pixel == 4 {
+        *(p as *mut u32) = value;
+    } else {
+        core::ptr::copy_nonoverlapping(value.to_le_bytes().as_ptr(), p, bytes_per_pixel as usize);
     }
 }
 
@@ -519,19 +1567,25 @@ trait Bitmap {
     fn width(&self) -> i64;
     fn height(&self) -> i64;
     fn bur_mut(&self) -> *mut u8;
+    // 色を、このBitmapのピクセルの形式の値に変換する
+    fn encode_color(&self, color: Color) -> u32;
 
-    unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
+    unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u8 {
         self.bur_mut().add(
             ((y * self.pixels_per_line() + x) * self.bytes_per_pixel()) as usize,
-        ) as *mut u32
+        )
     }
 
-    fn pixel_at_mut(&mut self, x: i64, y: i64) -> Option<&mut u32> {
-        
-        if self.is_in_x_range(x) && self.is_in_y_range(y) {
-            unsafe { Some(&mut *self.unchecked_pixel_at_mut(x, y)) }
-        } else {
-            None
+    unsafe fn unchecked_write_pixel(&mut self, x: i64, y: i64, color: Color) {
+        let value = self.encode_color(color);
+        write_pixel_value(self.unchecked_pixel_at_mut(x, y), value, self.bytes_per_pixel());
+    }
+
+    unsafe fn unchecked_fill_rect(&mut self, px: i64, py: i64, w: i64, h: i64, color: Color) {
+        for y in py..(py + h) {
+            for x in px..(px + w) {
+                self.unchecked_write_pixel(x, y, color);
+            }
         }
     }
 
@@ -549,12 +1603,15 @@ struct VramBufferInfo {
     pub height: i64,
     pub pixels_per_line: i64,
     pub buffer: *mut u8,
+    pub pixel_layout: PixelLayout,
+    // BltOnlyの画面モードでは、フレームバッファの代わりにGOPのBltで描画する
+    gop: *const EfiGraphicsOutputProtocol<'static>,
 }
 
-// BitmapトレイトをVramBufferInfo構造体に実装。bytes_per_pixelだけ4に固定
+// BitmapトレイトをVramBufferInfo構造体に実装。ピクセルの形式はGOPの画面モードに合わせる
 impl Bitmap for VramBufferInfo {
     fn bytes_per_pixel(&self) -> i64 {
-        4
+        self.pixel_layout.bytes_per_pixel()
     }
     fn pixels_per_line(&self) -> i64 {
         self.pixels_per_line
@@ -568,15 +1625,113 @@ impl Bitmap for VramBufferInfo {
     fn bur_mut(&self) -> *mut u8 {
         self.buffer
     }
+    fn encode_color(&self, color: Color) -> u32 {
+        self.pixel_layout.encode(color)
+    }
+
+    unsafe fn unchecked_write_pixel(&mut self, x: i64, y: i64, color: Color) {
+        if self.pixel_layout == PixelLayout::BltOnly {
+            self.blt_fill(x, y, 1, 1, color);
+        } else {
+            let value = self.encode_color(color);
+            write_pixel_value(self.unchecked_pixel_at_mut(x, y), value, self.bytes_per_pixel());
+        }
+    }
+
+    unsafe fn unchecked_fill_rect(&mut self, px: i64, py: i64, w: i64, h: i64, color: Color) {
+        if self.pixel_layout == PixelLayout::BltOnly {
+            // 1ピクセルずつBltを呼ぶと遅いので、まとめて塗りつぶす
+            self.blt_fill(px, py, w, h, color);
+        } else {
+            for y in py..(py + h) {
+                for x in px..(px + w) {
+                    self.unchecked_write_pixel(x, y, color);
+                }
+            }
+        }
+    }
 }
 
 fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
     
     let gp = locate_graphic_protolocol(efi_system_table)?;
-    Ok(VramBufferInfo{
-        width: gp.mode.info.horizontal_resolution as i64,
-        height: gp.mode.info.vertical_resolution as i64,
-        pixels_per_line: gp.mode.info.pixels_per_scan_line as i64,
-        buffer: gp.mode.frame_buffer_base as *mut u8,
-    })
-}
\ No newline at end of file
+    Ok(VramBufferInfo::from_gop(gp))
+}
+
+impl VramBufferInfo {
+    // GOPの今の画面モードから作る
+    // 解像度を切り替えたあとは、もう一度これを呼んで作り直す必要がある
+    fn from_gop(gp: &EfiGraphicsOutputProtocol) -> VramBufferInfo {
+        let pixel_layout = PixelLayout::from_info(gp.mode.info);
+        let width = gp.mode.info.horizontal_resolution as i64;
+        VramBufferInfo{
+            width,
+            height: gp.mode.info.vertical_resolution as i64,
+            // BltOnlyのときはpixels_per_scan_lineが意味を持たないので、横幅をそのまま使う
+            pixels_per_line: if pixel_layout == PixelLayout::BltOnly {
+                width
+            } else {
+                gp.mode.info.pixels_per_scan_line as i64
+            },
+            buffer: gp.mode.frame_buffer_base as *mut u8,
+            pixel_layout,
+            gop: (gp as *const EfiGraphicsOutputProtocol).cast(),
+        }
+    }
+
+    // Bltで矩形を塗りつぶす。Boot Servicesが終了した後は何もしない
+    fn blt_fill(&self, x: i64, y: i64, w: i64, h: i64, color: Color) {
+        if !boot_services_active() {
+            return;
+        }
+        if let Some(gp) = unsafe { self.gop.as_ref() } {
+            let _ = gp.blt_fill(x as usize, y as usize, w as usize, h as usize, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_runner::system_table;
+
+    #[test_case]
+    fn efi_status_into_result() {
+        assert_eq!(EfiStatus::SUCCESS.into_result(), Ok(()));
+        assert_eq!(EfiStatus::NOT_FOUND.into_result(), Err(EfiStatus::NOT_FOUND));
+        assert!(EfiStatus::NOT_FOUND.is_error());
+        assert!(!EfiStatus::SUCCESS.is_error());
+    }
+
+    #[test_case]
+    fn to_ucs2_is_nul_terminated() {
+        assert_eq!(to_ucs2("EFI"), [b'E' as u16, b'F' as u16, b'I' as u16, 0]);
+        assert_eq!(to_ucs2("\u{1f363}"), ['?' as u16, 0]);
+    }
+
+    #[test_case]
+    fn memory_map_iterator_visits_every_descriptor() {
+        let mut memory_map = MemoryMapHolder::new();
+        system_table()
+            .boot_services
+            .get_memory_map(&mut memory_map)
+            .expect("get_memory_map failed");
+        let count = memory_map.iter().count();
+        assert!(count > 0);
+        assert_eq!(count, memory_map.memory_map_size / memory_map.descriptor_size);
+        assert!(memory_map.iter().all(|e| e.number_of_pages > 0));
+        assert!(memory_map.iter().any(|e| e.memory_type == EfiMemoryType::CONVENTIONAL_MEMORY));
+    }
+
+    #[test_case]
+    fn memory_map_iterator_is_empty_when_invalid() {
+        assert_eq!(MemoryMapHolder::new().iter().count(), 0);
+    }
+
+    #[test_case]
+    fn init_vram_returns_current_mode() {
+        let vram = init_vram(system_table()).expect("init_vram failed");
+        assert!(vram.width > 0 && vram.height > 0);
+        assert!(vram.pixels_per_line >= vram.width);
+    }
+}
@@ -0,0 +1,313 @@
+// メモリマップ(MemoryMapHolder)を解析するための機能
+// - 隣り合う同じ種類の領域をまとめる
+// - attributeのビットを読みやすくする
+// - 種類ごとの合計ページ数を数える
+
+use core::fmt;
+use core::iter::Peekable;
+
+use crate::EfiMemoryDescriptor;
+use crate::EfiMemoryType;
+use crate::MemoryMapHolder;
+use crate::MemoryMapIterator;
+
+// メモリマップのページの大きさ(number_of_pagesの単位)は常に4KiB
+pub const EFI_PAGE_SIZE: u64 = 4096;
+
+// EfiMemoryDescriptor::attributeのビット
+// 領域がどのキャッシュ方式に対応しているか、どんな保護ができるかを表す
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[repr(transparent)]
+pub struct MemoryAttribute(pub u64);
+
+impl MemoryAttribute {
+    pub const UC: MemoryAttribute = MemoryAttribute(0x1); // キャッシュ無効
+    pub const WC: MemoryAttribute = MemoryAttribute(0x2); // ライトコンバイン
+    pub const WT: MemoryAttribute = MemoryAttribute(0x4); // ライトスルー
+    pub const WB: MemoryAttribute = MemoryAttribute(0x8); // ライトバック
+    pub const UCE: MemoryAttribute = MemoryAttribute(0x10);
+    pub const WP: MemoryAttribute = MemoryAttribute(0x1000); // 書き込み保護
+    pub const RP: MemoryAttribute = MemoryAttribute(0x2000); // 読み込み保護
+    pub const XP: MemoryAttribute = MemoryAttribute(0x4000); // 実行禁止
+    pub const NV: MemoryAttribute = MemoryAttribute(0x8000);
+    pub const MORE_RELIABLE: MemoryAttribute = MemoryAttribute(0x1_0000);
+    pub const RO: MemoryAttribute = MemoryAttribute(0x2_0000);
+    pub const SP: MemoryAttribute = MemoryAttribute(0x4_0000);
+    pub const CPU_CRYPTO: MemoryAttribute = MemoryAttribute(0x8_0000);
+    // Runtime Servicesが使うので、仮想アドレスへの変換が必要な領域
+    pub const RUNTIME: MemoryAttribute = MemoryAttribute(0x8000_0000_0000_0000);
+
+    const NAMES: [(MemoryAttribute, &'static str); 14] = [
+        (Self::UC, "UC"),
+        (Self::WC, "WC"),
+        (Self::WT, "WT"),
+        (Self::WB, "WB"),
+        (Self::UCE, "UCE"),
+        (Self::WP, "WP"),
+        (Self::RP, "RP"),
+        (Self::XP, "XP"),
+        (Self::NV, "NV"),
+        (Self::MORE_RELIABLE, "MORE_RELIABLE"),
+        (Self::RO, "RO"),
+        (Self::SP, "SP"),
+        (Self::CPU_CRYPTO, "CPU_CRYPTO"),
+        (Self::RUNTIME, "RUNTIME"),
+    ];
+
+    pub fn contains(self, other: MemoryAttribute) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // 両方の領域で共通して使える属性だけを残す
+    pub fn intersection(self, other: MemoryAttribute) -> MemoryAttribute {
+        MemoryAttribute(self.0 & other.0)
+    }
+}
+
+impl fmt::Debug for MemoryAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.0;
+        let mut first = true;
+        for (attr, name) in Self::NAMES {
+            if !self.contains(attr) {
+                continue;
+            }
+            if !first {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
+            rest &= !attr.0;
+            first = false;
+        }
+        // 名前のついていないビットは数値のまま表示する
+        if rest != 0 {
+            if !first {
+                f.write_str("|")?;
+            }
+            write!(f, "{rest:#x}")?;
+            first = false;
+        }
+        if first {
+            f.write_str("NONE")?;
+        }
+        Ok(())
+    }
+}
+
+impl EfiMemoryDescriptor {
+    pub fn attribute(&self) -> MemoryAttribute {
+        MemoryAttribute(self.attribute)
+    }
+}
+
+// 物理アドレスが連続していて、同じ種類のメモリ領域
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct MemoryRegion {
+    pub memory_type: EfiMemoryType,
+    pub physical_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: MemoryAttribute,
+}
+
+impl MemoryRegion {
+    pub fn size(&self) -> u64 {
+        self.number_of_pages * EFI_PAGE_SIZE
+    }
+
+    // 領域の終わり(この領域に含まれない最初のアドレス)
+    pub fn physical_end(&self) -> u64 {
+        self.physical_start + self.size()
+    }
+}
+
+impl From<&EfiMemoryDescriptor> for MemoryRegion {
+    fn from(e: &EfiMemoryDescriptor) -> Self {
+        MemoryRegion {
+            memory_type: e.memory_type,
+            physical_start: e.physical_start,
+            number_of_pages: e.number_of_pages,
+            attribute: e.attribute(),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:#012x}-{:#012x} {} pages {:?}",
+            self.memory_type,
+            self.physical_start,
+            self.physical_end(),
+            self.number_of_pages,
+            self.attribute
+        )
+    }
+}
+
+// 隣り合うエントリが同じ種類で、物理アドレスが連続していれば1つの領域にまとめるイテレータ
+// まとめた領域のattributeは、すべてのエントリで共通しているビットだけになる
+// (メモリマップはファームウェアが返した順番のまま走査するので、並べ替えはしない)
+pub struct CoalescedMemoryMapIterator<'a> {
+    inner: Peekable<MemoryMapIterator<'a>>,
+}
+
+impl Iterator for CoalescedMemoryMapIterator<'_> {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<MemoryRegion> {
+        let mut region = MemoryRegion::from(self.inner.next()?);
+        while let Some(e) = self
+            .inner
+            .next_if(|e| e.memory_type == region.memory_type && e.physical_start == region.physical_end())
+        {
+            region.number_of_pages += e.number_of_pages;
+            region.attribute = region.attribute.intersection(e.attribute());
+        }
+        Some(region)
+    }
+}
+
+// メモリの種類ごとの合計ページ数
+pub struct MemoryMapStatistics {
+    pages: [u64; EfiMemoryType::NUM_STANDARD_TYPES],
+    oem_reserved_pages: u64,
+    os_reserved_pages: u64,
+    unknown_pages: u64,
+}
+
+impl MemoryMapStatistics {
+    fn new() -> Self {
+        MemoryMapStatistics {
+            pages: [0; EfiMemoryType::NUM_STANDARD_TYPES],
+            oem_reserved_pages: 0,
+            os_reserved_pages: 0,
+            unknown_pages: 0,
+        }
+    }
+
+    fn add(&mut self, e: &EfiMemoryDescriptor) {
+        let counter = if let Some(pages) = self.pages.get_mut(e.memory_type.0 as usize) {
+            pages
+        } else if e.memory_type.is_oem_reserved() {
+            &mut self.oem_reserved_pages
+        } else if e.memory_type.is_os_reserved() {
+            &mut self.os_reserved_pages
+        } else {
+            &mut self.unknown_pages
+        };
+        *counter += e.number_of_pages;
+    }
+
+    // 指定した種類の合計ページ数
+    // OEMやOSが独自に定義した種類は、それぞれまとめて数えている
+    pub fn pages(&self, memory_type: EfiMemoryType) -> u64 {
+        if let Some(pages) = self.pages.get(memory_type.0 as usize) {
+            *pages
+        } else if memory_type.is_oem_reserved() {
+            self.oem_reserved_pages
+        } else if memory_type.is_os_reserved() {
+            self.os_reserved_pages
+        } else {
+            self.unknown_pages
+        }
+    }
+
+    pub fn total_pages(&self) -> u64 {
+        self.pages.iter().sum::<u64>()
+            + self.oem_reserved_pages
+            + self.os_reserved_pages
+            + self.unknown_pages
+    }
+
+    // 仕様で定義されている種類のうち、1ページ以上あるものを列挙する
+    pub fn iter(&self) -> impl Iterator<Item = (EfiMemoryType, u64)> + '_ {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter(|(_, pages)| **pages != 0)
+            .map(|(i, pages)| (EfiMemoryType(i as u32), *pages))
+    }
+}
+
+impl MemoryMapHolder {
+    pub fn regions(&self) -> CoalescedMemoryMapIterator {
+        CoalescedMemoryMapIterator {
+            inner: self.iter().peekable(),
+        }
+    }
+
+    pub fn statistics(&self) -> MemoryMapStatistics {
+        let mut stats = MemoryMapStatistics::new();
+        for e in self.iter() {
+            stats.add(e);
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::size_of;
+    use core::mem::size_of_val;
+
+    use crate::EFI_MEMORY_DESCRIPTOR_VERSION;
+
+    fn descriptor(memory_type: EfiMemoryType, physical_start: u64, pages: u64) -> EfiMemoryDescriptor {
+        EfiMemoryDescriptor {
+            memory_type,
+            physical_start,
+            virtual_start: 0,
+            number_of_pages: pages,
+            attribute: MemoryAttribute::WB.0 | MemoryAttribute::UC.0,
+        }
+    }
+
+    fn holder(descriptors: &mut [EfiMemoryDescriptor]) -> MemoryMapHolder {
+        let size = size_of_val(descriptors);
+        MemoryMapHolder {
+            memory_map_buffer: descriptors.as_mut_ptr() as *mut u8,
+            memory_map_buffer_size: size,
+            memory_map_size: size,
+            map_key: 0,
+            descriptor_size: size_of::<EfiMemoryDescriptor>(),
+            descriptor_version: EFI_MEMORY_DESCRIPTOR_VERSION,
+        }
+    }
+
+    #[test_case]
+    fn regions_merge_only_contiguous_entries_of_same_type() {
+        let conventional = EfiMemoryType::CONVENTIONAL_MEMORY;
+        let mut descriptors = [
+            descriptor(conventional, 0x0000, 1),
+            descriptor(conventional, 0x1000, 2),
+            descriptor(EfiMemoryType::LOADER_DATA, 0x3000, 1),
+            descriptor(conventional, 0x4000, 1),
+            // 隙間があるのでまとめない
+            descriptor(conventional, 0x8000, 1),
+        ];
+        let map = holder(&mut descriptors);
+        let mut regions = map.regions();
+        let r = regions.next().unwrap();
+        assert_eq!((r.memory_type, r.physical_start, r.number_of_pages), (conventional, 0, 3));
+        assert_eq!(regions.next().unwrap().memory_type, EfiMemoryType::LOADER_DATA);
+        assert_eq!(regions.next().unwrap().physical_start, 0x4000);
+        assert_eq!(regions.next().unwrap().physical_start, 0x8000);
+        assert!(regions.next().is_none());
+    }
+
+    #[test_case]
+    fn statistics_count_pages_per_type() {
+        let mut descriptors = [
+            descriptor(EfiMemoryType::CONVENTIONAL_MEMORY, 0x0000, 3),
+            descriptor(EfiMemoryType::LOADER_DATA, 0x3000, 2),
+            descriptor(EfiMemoryType(0x8000_0000), 0x5000, 4),
+        ];
+        let stats = holder(&mut descriptors).statistics();
+        assert_eq!(stats.pages(EfiMemoryType::CONVENTIONAL_MEMORY), 3);
+        assert_eq!(stats.pages(EfiMemoryType::LOADER_DATA), 2);
+        assert_eq!(stats.pages(EfiMemoryType(0x8000_0001)), 4);
+        assert_eq!(stats.total_pages(), 9);
+    }
+}
@@ -0,0 +1,652 @@
+// 4段のページテーブル(PML4 → PDPT → PD → PT)
+// OVMFが用意したページテーブルの代わりに、frame_allocatorから確保したページで自前のものを作ってCR3を切り替える
+// - 物理メモリは同じアドレスに写す(identity map)。ページテーブル自身もこのアドレスで読み書きする
+// - カーネルのイメージは、上位のアドレス(KERNEL_VIRTUAL_BASE)にも写しておく
+// - GOPのフレームバッファは、物理メモリより上にあっても写す。PATを設定して、Write-Combiningにする
+// 揃っているところは、2MiBや1GiBのページでまとめて写す
+// 0番地のページはNULLポインタの参照を捕まえるために写さない
+// 書き込めるページは実行できないようにする(W^X)
+// - イメージはPEのセクションごとに、.textは読み出しと実行、.rdataは読み出しだけ、.dataは読み書きだけ
+// - Runtime Servicesのイメージは、Memory Attributes Tableがあればそれに従ってセクションごとに制限する
+//   ない場合は、RUNTIME_SERVICE_CODEを読み書きも実行もできるようにする(W^Xの唯一の例外)
+// - それ以外の物理メモリは読み書きだけ
+// - スタックのすぐ下のページは写さず、あふれたらページフォルトになるようにする(ガードページ)
+
+use core::fmt;
+
+use crate::backtrace::image_range;
+use crate::efi_allocator::EFI_ALLOCATOR;
+use crate::frame_allocator;
+use crate::frame_allocator::PAGE_SIZE;
+use crate::mutex::Mutex;
+use crate::pe::sections;
+use crate::pe::PeSectionHeader;
+use crate::x86::has_1gib_pages;
+use crate::x86::has_nx;
+use crate::x86::has_pat;
+use crate::x86::invlpg;
+use crate::x86::rdmsr;
+use crate::x86::read_cr0;
+use crate::x86::read_cr3;
+use crate::x86::wbinvd;
+use crate::x86::write_cr0;
+use crate::x86::write_cr3;
+use crate::x86::wrmsr;
+use crate::x86::CR0_WP;
+use crate::x86::EFER_NXE;
+use crate::x86::MSR_IA32_EFER;
+use crate::x86::MSR_IA32_PAT;
+use crate::memory_map::MemoryAttribute;
+use crate::EfiMemoryDescriptor;
+use crate::EfiMemoryType;
+use crate::MemoryMapHolder;
+use crate::Result;
+
+// カーネルのイメージを写す場所(上位2GiB)
+pub const KERNEL_VIRTUAL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+// 4GiBまではメモリマップに載っていないMMIO(ローカルAPICなど)があるので、すべて写す
+const MIN_IDENTITY_MAP_END: u64 = 4 * 1024 * 1024 * 1024;
+
+// PATの8つのエントリ(1バイトずつ)。ページはPAT<<2 | PCD<<1 | PWTのエントリのメモリタイプになる
+// 電源投入時の値(WB, WT, UC-, UC)のうち、1番(PWTだけ)をWTからWC(Write-Combining)に変える
+const PAT_VALUE: u64 = 0x0007_0106_0007_0106;
+
+const ENTRIES_PER_TABLE: usize = 512;
+// エントリのうち、次の段のテーブル(またはページ)の物理アドレスの部分
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// ページテーブルのエントリのフラグ
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[repr(transparent)]
+pub struct PageFlags(pub u64);
+
+impl PageFlags {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const USER: u64 = 1 << 2;
+    pub const WRITE_THROUGH: u64 = 1 << 3;
+    pub const CACHE_DISABLE: u64 = 1 << 4;
+    pub const ACCESSED: u64 = 1 << 5;
+    pub const DIRTY: u64 = 1 << 6;
+    // PDPTやPDのエントリで立っていれば、1GiBや2MiBのページそのものを指している
+    pub const HUGE_PAGE: u64 = 1 << 7;
+    pub const GLOBAL: u64 = 1 << 8;
+    pub const NO_EXECUTE: u64 = 1 << 63;
+    // init()でPATを設定した後は、PWTだけを立てるとWrite-Combiningになる
+    pub const WRITE_COMBINING: u64 = Self::WRITE_THROUGH;
+
+    const NAMES: [(u64, &'static str); 10] = [
+        (Self::PRESENT, "P"),
+        (Self::WRITABLE, "W"),
+        (Self::USER, "U"),
+        (Self::WRITE_THROUGH, "PWT"),
+        (Self::CACHE_DISABLE, "PCD"),
+        (Self::ACCESSED, "A"),
+        (Self::DIRTY, "D"),
+        (Self::HUGE_PAGE, "PS"),
+        (Self::GLOBAL, "G"),
+        (Self::NO_EXECUTE, "NX"),
+    ];
+
+    fn contains(self, flag: u64) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+impl fmt::Debug for PageFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (flag, name) in Self::NAMES {
+            if !self.contains(flag) {
+                continue;
+            }
+            if !first {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        if first {
+            f.write_str("NONE")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+#[repr(transparent)]
+struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    fn is_present(self) -> bool {
+        self.0 & PageFlags::PRESENT != 0
+    }
+
+    fn address(self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    fn flags(self) -> PageFlags {
+        PageFlags(self.0 & !ADDRESS_MASK)
+    }
+
+    // levelの段のエントリが、次の段のテーブルではなくページを指しているか(levelは4がPML4、1がPT)
+    fn is_page(self, level: u32) -> bool {
+        level == 1 || (level <= 3 && self.flags().contains(PageFlags::HUGE_PAGE))
+    }
+}
+
+const LEVEL_NAMES: [&str; 4] = ["PT", "PD", "PDPT", "PML4"];
+
+// levelの段のエントリ1つが受け持つ大きさ
+fn level_size(level: u32) -> u64 {
+    PAGE_SIZE << (9 * (level - 1))
+}
+
+fn table_index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * (level - 1))) as usize) % ENTRIES_PER_TABLE
+}
+
+// 48ビットの仮想アドレスは、上位16ビットがビット47と同じでなければならない
+fn is_canonical(virt: u64) -> bool {
+    ((virt as i64) << 16 >> 16) as u64 == virt
+}
+
+// NO_EXECUTEが使えないCPUでは、そのビットは予約されているので立てない
+fn supported_flags(flags: PageFlags) -> u64 {
+    if has_nx() {
+        flags.0
+    } else {
+        flags.0 & !PageFlags::NO_EXECUTE
+    }
+}
+
+// 物理アドレスにあるテーブルを読み書きする(物理メモリはidentity mapされている)
+unsafe fn table_at(address: u64) -> &'static mut [PageTableEntry; ENTRIES_PER_TABLE] {
+    &mut *(address as *mut [PageTableEntry; ENTRIES_PER_TABLE])
+}
+
+// 0で埋めたページテーブル用のページを確保する
+// Boot Servicesが使えるうちは(テストなど)、AllocatePagesから確保する
+fn allocate_table() -> Result<u64> {
+    let table = match EFI_ALLOCATOR.allocate_pages(1) {
+        Some(address) => address,
+        None => frame_allocator::allocate_frames(1)?,
+    };
+    unsafe { (table as *mut u8).write_bytes(0, PAGE_SIZE as usize) };
+    Ok(table)
+}
+
+// 1つのページテーブル(の木)。PML4の物理アドレスで表す
+pub struct AddressSpace {
+    pml4: u64,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace> {
+        Ok(AddressSpace {
+            pml4: allocate_table()?,
+        })
+    }
+
+    // 今CR3に入っているもの
+    pub fn current() -> AddressSpace {
+        AddressSpace {
+            pml4: read_cr3() & ADDRESS_MASK,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        read_cr3() & ADDRESS_MASK == self.pml4
+    }
+
+    // levelの段の、virtを受け持つエントリを返す。途中のテーブルがなければ、createのときだけ作る
+    fn entry(&mut self, virt: u64, level: u32, create: bool) -> Result<Option<&'static mut PageTableEntry>> {
+        let mut table = self.pml4;
+        for upper in (level + 1..=4).rev() {
+            let entry = unsafe { &mut table_at(table)[table_index(virt, upper)] };
+            if !entry.is_present() {
+                if !create {
+                    return Ok(None);
+                }
+                // 書き込みなどの制限は最後の段のエントリで決める
+                *entry = PageTableEntry(allocate_table()? | PageFlags::PRESENT | PageFlags::WRITABLE);
+            } else if entry.is_page(upper) {
+                return Err("Address is mapped by a huge page");
+            }
+            table = entry.address();
+        }
+        Ok(Some(unsafe { &mut table_at(table)[table_index(virt, level)] }))
+    }
+
+    // virtを写しているエントリと、その段を返す
+    fn find_page(&self, virt: u64) -> Option<(&'static mut PageTableEntry, u32)> {
+        let mut table = self.pml4;
+        for level in [4, 3, 2, 1] {
+            let entry = unsafe { &mut table_at(table)[table_index(virt, level)] };
+            if !entry.is_present() {
+                return None;
+            }
+            if entry.is_page(level) {
+                return Some((entry, level));
+            }
+            table = entry.address();
+        }
+        None
+    }
+
+    // [virt, virt + size)を[phys, phys + size)に写す。すでに写されているページがあればエラーにする
+    // virtとphysがどちらも揃っていて大きさも足りるところは、2MiBや1GiBのページで写す
+    pub fn map(&mut self, virt: u64, phys: u64, size: u64, flags: PageFlags) -> Result<()> {
+        if virt % PAGE_SIZE != 0 || phys % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err("Mapping must be page aligned");
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        if !is_canonical(virt) || !is_canonical(virt + size - 1) {
+            return Err("Virtual address is not canonical");
+        }
+        let max_level = if has_1gib_pages() { 3 } else { 2 };
+        // PTのエントリでは、HUGE_PAGEのビットはPATの意味になる
+        let flags = supported_flags(flags) & !PageFlags::HUGE_PAGE;
+        let mut offset = 0;
+        while offset < size {
+            let level = (2..=max_level)
+                .rev()
+                .find(|&level| {
+                    let page_size = level_size(level);
+                    (virt + offset) % page_size == 0 && (phys + offset) % page_size == 0 && size - offset >= page_size
+                })
+                .unwrap_or(1);
+            let entry = self.entry(virt + offset, level, true)?.ok_or("Page table not found")?;
+            if entry.is_present() {
+                return Err("Address is already mapped");
+            }
+            let huge_page = if level > 1 { PageFlags::HUGE_PAGE } else { 0 };
+            *entry = PageTableEntry((phys + offset) | flags | huge_page | PageFlags::PRESENT);
+            offset += level_size(level);
+        }
+        Ok(())
+    }
+
+    // [virt, virt + size)を写さないようにする。途中のテーブルは残しておく
+    // 大きいページの一部だけを外すことはできない
+    pub fn unmap(&mut self, virt: u64, size: u64) -> Result<()> {
+        if virt % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err("Mapping must be page aligned");
+        }
+        let is_active = self.is_active();
+        let mut offset = 0;
+        while offset < size {
+            let (entry, level) = self.find_page(virt + offset).ok_or("Address is not mapped")?;
+            let page_size = level_size(level);
+            if (virt + offset) % page_size != 0 || size - offset < page_size {
+                return Err("Cannot unmap a part of a huge page");
+            }
+            *entry = PageTableEntry(0);
+            if is_active {
+                invlpg(virt + offset);
+            }
+            offset += page_size;
+        }
+        Ok(())
+    }
+
+    // virtを含む大きいページを、1つ下の段のページ512個に分ける。4KiBのページになるまで繰り返す
+    // 写し先とフラグは変わらない
+    fn split(&mut self, virt: u64) -> Result<()> {
+        loop {
+            let (entry, level) = self.find_page(virt).ok_or("Address is not mapped")?;
+            if level == 1 {
+                return Ok(());
+            }
+            let table = allocate_table()?;
+            let page_size = level_size(level - 1);
+            let huge_page = if level - 1 > 1 { PageFlags::HUGE_PAGE } else { 0 };
+            let flags = entry.flags().0 & !PageFlags::HUGE_PAGE;
+            for (i, sub_entry) in unsafe { table_at(table) }.iter_mut().enumerate() {
+                *sub_entry = PageTableEntry((entry.address() + i as u64 * page_size) | flags | huge_page);
+            }
+            *entry = PageTableEntry(table | PageFlags::PRESENT | PageFlags::WRITABLE);
+            if self.is_active() {
+                invlpg(virt);
+            }
+        }
+    }
+
+    // 写されている[virt, virt + size)のフラグを変える。大きいページの一部だけなら、分けてから変える
+    pub fn protect(&mut self, virt: u64, size: u64, flags: PageFlags) -> Result<()> {
+        if virt % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err("Mapping must be page aligned");
+        }
+        let flags = supported_flags(flags) & !PageFlags::HUGE_PAGE;
+        let is_active = self.is_active();
+        let mut offset = 0;
+        while offset < size {
+            let (entry, level) = self.find_page(virt + offset).ok_or("Address is not mapped")?;
+            let page_size = level_size(level);
+            if (virt + offset) % page_size != 0 || size - offset < page_size {
+                self.split(virt + offset)?;
+                continue;
+            }
+            let huge_page = if level > 1 { PageFlags::HUGE_PAGE } else { 0 };
+            *entry = PageTableEntry(entry.address() | flags | huge_page | PageFlags::PRESENT);
+            if is_active {
+                invlpg(virt + offset);
+            }
+            offset += page_size;
+        }
+        Ok(())
+    }
+
+    // virtのページを写さないようにして、触るとページフォルトになるようにする
+    pub fn add_guard_page(&mut self, virt: u64) -> Result<()> {
+        self.split(virt)?;
+        self.unmap(virt, PAGE_SIZE)
+    }
+
+    // 仮想アドレスを物理アドレスに変換する
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        let (entry, level) = self.find_page(virt)?;
+        let offset_mask = level_size(level) - 1;
+        Some((entry.address() & !offset_mask) | (virt & offset_mask))
+    }
+
+    // virtを変換するときにたどるエントリを、段ごとに表示する
+    pub fn dump(&self, w: &mut impl fmt::Write, virt: u64) -> fmt::Result {
+        writeln!(w, "Page walk for {virt:#018x} (PML4 at {:#x}):", self.pml4)?;
+        let mut table = self.pml4;
+        for level in [4, 3, 2, 1] {
+            let index = table_index(virt, level);
+            let entry = unsafe { table_at(table)[index] };
+            writeln!(
+                w,
+                "  {:>4}[{index:3}] = {:#018x} {:?}",
+                LEVEL_NAMES[level as usize - 1],
+                entry.address(),
+                entry.flags()
+            )?;
+            if !entry.is_present() || entry.is_page(level) {
+                break;
+            }
+            table = entry.address();
+        }
+        match self.translate(virt) {
+            Some(phys) => writeln!(w, "  -> {phys:#018x}"),
+            None => writeln!(w, "  -> not mapped"),
+        }
+    }
+}
+
+// CR3に入れたカーネルのページテーブル
+static KERNEL_ADDRESS_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+// 物理アドレスと同じ仮想アドレスに[start, end)を写す
+fn map_identity(space: &mut AddressSpace, start: u64, end: u64, flags: PageFlags) -> Result<()> {
+    if start < end {
+        space.map(start, start, end - start, flags)
+    } else {
+        Ok(())
+    }
+}
+
+// イメージのoffsetから始まるページのフラグ。ヘッダやセクションの間は読み出しだけにする
+// 1つのページに複数のセクションがかかっていれば、どれかが許していることは許す
+fn image_page_flags(sections: &[PeSectionHeader], offset: u64) -> PageFlags {
+    let mut flags = PageFlags::NO_EXECUTE;
+    for section in sections {
+        let (start, end) = section.range();
+        if start < offset + PAGE_SIZE && offset < end {
+            if section.is_writable() {
+                flags |= PageFlags::WRITABLE;
+            }
+            if section.is_executable() {
+                flags &= !PageFlags::NO_EXECUTE;
+            }
+        }
+    }
+    PageFlags(flags)
+}
+
+// Memory Attributes Tableのエントリの属性を、ページのフラグにする
+fn runtime_page_flags(attribute: MemoryAttribute) -> PageFlags {
+    let mut flags = 0;
+    if !attribute.contains(MemoryAttribute::RO) {
+        flags |= PageFlags::WRITABLE;
+    }
+    if attribute.contains(MemoryAttribute::XP) {
+        flags |= PageFlags::NO_EXECUTE;
+    }
+    PageFlags(flags)
+}
+
+// ExitBootServicesとframe_allocator::initの後に一度だけ呼ぶ
+// runtime_attributes: ExitBootServicesの前に写しておいたMemory Attributes Tableのエントリ(なければ空)
+// framebuffer: フレームバッファの(先頭の物理アドレス, 大きさ)。大きさが0ならフレームバッファはない
+pub fn init(
+    memory_map: &MemoryMapHolder,
+    framebuffer: (u64, u64),
+    runtime_attributes: &[EfiMemoryDescriptor],
+) -> Result<()> {
+    let mut space = AddressSpace::new()?;
+    let flags = PageFlags(PageFlags::WRITABLE | PageFlags::NO_EXECUTE);
+    // PATがなければ、フレームバッファも普通のメモリと同じように写す
+    let pat = has_pat();
+    let fb_flags = if pat {
+        PageFlags(flags.0 | PageFlags::WRITE_COMBINING)
+    } else {
+        flags
+    };
+    let end = memory_map
+        .iter()
+        .map(|e| e.physical_start + e.number_of_pages * PAGE_SIZE)
+        .max()
+        .unwrap_or(0)
+        .max(MIN_IDENTITY_MAP_END)
+        .next_multiple_of(PAGE_SIZE);
+    let (fb_start, fb_size) = framebuffer;
+    if fb_size == 0 {
+        map_identity(&mut space, PAGE_SIZE, end, flags)?;
+    } else {
+        let fb_end = (fb_start + fb_size).next_multiple_of(PAGE_SIZE);
+        let fb_start = (fb_start - fb_start % PAGE_SIZE).max(PAGE_SIZE);
+        // フレームバッファの前後は普通のメモリとして写す
+        map_identity(&mut space, PAGE_SIZE, fb_start.min(end), flags)?;
+        map_identity(&mut space, fb_end.max(PAGE_SIZE), end, flags)?;
+        map_identity(&mut space, fb_start, fb_end, fb_flags)?;
+    }
+    // SetVirtualAddressMapの後も、Runtime Servicesのコードはこのアドレスで呼び出す
+    for e in runtime_attributes {
+        space.protect(e.physical_start, e.number_of_pages * PAGE_SIZE, runtime_page_flags(e.attribute()))?;
+    }
+    // Memory Attributes Tableがなければ、イメージのどこがコードでどこがデータか分からない
+    // 呼び出せなくなると困るので、わざとW^Xの例外として、読み書きも実行もできるようにしておく
+    if runtime_attributes.is_empty() {
+        for e in memory_map.iter().filter(|e| e.memory_type == EfiMemoryType::RUNTIME_SERVICE_CODE) {
+            space.protect(e.physical_start, e.number_of_pages * PAGE_SIZE, PageFlags(PageFlags::WRITABLE))?;
+        }
+    }
+    // 今実行しているのは物理アドレスのほうなので、そちらもセクションごとに制限する
+    let (image_base, image_size) = image_range();
+    if image_size != 0 {
+        let sections = sections(image_base)?;
+        for offset in (0..image_size.next_multiple_of(PAGE_SIZE)).step_by(PAGE_SIZE as usize) {
+            let flags = image_page_flags(sections, offset);
+            space.protect(image_base + offset, PAGE_SIZE, flags)?;
+            space.map(KERNEL_VIRTUAL_BASE + offset, image_base + offset, PAGE_SIZE, flags)?;
+        }
+    }
+    // NO_EXECUTEのビットは、EFER.NXEを立てるまでは予約ビットなので、切り替える前に有効にする
+    if has_nx() {
+        unsafe { wrmsr(MSR_IA32_EFER, rdmsr(MSR_IA32_EFER) | EFER_NXE) };
+    }
+    if pat {
+        unsafe { wrmsr(MSR_IA32_PAT, PAT_VALUE) };
+    }
+    // 今実行しているコードとスタックは、どちらも物理アドレスのまま写っている
+    // CR3を書き換えるとTLBが消えるので、古いメモリタイプのキャッシュも書き戻して捨てる
+    unsafe { write_cr3(space.pml4) };
+    wbinvd();
+    // CR0.WPがないと、カーネルは読み出しだけのページにも書き込めてしまう
+    unsafe { write_cr0(read_cr0() | CR0_WP) };
+    *KERNEL_ADDRESS_SPACE.lock() = Some(space);
+    Ok(())
+}
+
+// カーネルのページテーブルで、virtのページをガードページにする
+pub fn add_guard_page(virt: u64) -> Result<()> {
+    KERNEL_ADDRESS_SPACE
+        .lock()
+        .as_mut()
+        .ok_or("Paging is not initialized")?
+        .add_guard_page(virt)
+}
+
+// pagesページのスタックを、その下のガードページと一緒に確保する。スタックの一番上(rspの初期値)を返す
+pub fn allocate_stack(pages: u64) -> Result<u64> {
+    let base = frame_allocator::allocate_frames(pages + 1)?;
+    if let Err(e) = add_guard_page(base) {
+        frame_allocator::free_frames(base, pages + 1)?;
+        return Err(e);
+    }
+    Ok(base + (pages + 1) * PAGE_SIZE)
+}
+
+// カーネルのページテーブルで、[virt, virt + size)を[phys, phys + size)に写す
+#[allow(dead_code)]
+pub fn map(virt: u64, phys: u64, size: u64, flags: PageFlags) -> Result<()> {
+    KERNEL_ADDRESS_SPACE
+        .lock()
+        .as_mut()
+        .ok_or("Paging is not initialized")?
+        .map(virt, phys, size, flags)
+}
+
+#[allow(dead_code)]
+pub fn unmap(virt: u64, size: u64) -> Result<()> {
+    KERNEL_ADDRESS_SPACE
+        .lock()
+        .as_mut()
+        .ok_or("Paging is not initialized")?
+        .unmap(virt, size)
+}
+
+// init()の前は、ファームウェアのページテーブルで変換する
+#[allow(dead_code)]
+pub fn translate(virt: u64) -> Option<u64> {
+    match KERNEL_ADDRESS_SPACE.lock().as_ref() {
+        Some(space) => space.translate(virt),
+        None => AddressSpace::current().translate(virt),
+    }
+}
+
+// pagewalkコマンド
+pub fn dump(w: &mut impl fmt::Write, virt: u64) -> fmt::Result {
+    if !is_canonical(virt) {
+        return writeln!(w, "{virt:#x} is not a canonical address");
+    }
+    match KERNEL_ADDRESS_SPACE.lock().as_ref() {
+        Some(space) => space.dump(w, virt),
+        None => AddressSpace::current().dump(w, virt),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn address_space_maps_translates_and_unmaps() {
+        let mut space = AddressSpace::new().unwrap();
+        let flags = PageFlags(PageFlags::WRITABLE);
+        // 2MiBの境界をまたいで、PTが2つ必要になるように写す
+        let virt = KERNEL_VIRTUAL_BASE + 0x1f_f000;
+        space.map(virt, 0x1234_5000, 2 * PAGE_SIZE, flags).unwrap();
+        assert_eq!(space.translate(virt + 0x123), Some(0x1234_5123));
+        assert_eq!(space.translate(virt + PAGE_SIZE), Some(0x1234_6000));
+        assert_eq!(space.translate(virt + 2 * PAGE_SIZE), None);
+        assert!(space.map(virt, 0, PAGE_SIZE, flags).is_err());
+        space.unmap(virt, PAGE_SIZE).unwrap();
+        assert_eq!(space.translate(virt), None);
+        assert!(space.unmap(virt, PAGE_SIZE).is_err());
+        assert_eq!(space.translate(virt + PAGE_SIZE), Some(0x1234_6000));
+    }
+
+    #[test_case]
+    fn address_space_uses_huge_pages() {
+        let mut space = AddressSpace::new().unwrap();
+        let flags = PageFlags(PageFlags::WRITABLE);
+        let huge = level_size(2);
+        // 先頭の4KiB、2MiBのページが2つ、最後の4KiBに分かれる
+        let virt = KERNEL_VIRTUAL_BASE + huge - PAGE_SIZE;
+        let phys = 0x4000_0000 + huge - PAGE_SIZE;
+        space.map(virt, phys, 2 * huge + 2 * PAGE_SIZE, flags).unwrap();
+        let level = |space: &AddressSpace, virt| space.find_page(virt).map(|(_, level)| level);
+        assert_eq!(level(&space, virt), Some(1));
+        assert_eq!(level(&space, virt + PAGE_SIZE), Some(2));
+        assert_eq!(level(&space, virt + huge + PAGE_SIZE), Some(2));
+        assert_eq!(level(&space, virt + 2 * huge + PAGE_SIZE), Some(1));
+        assert_eq!(space.translate(virt + PAGE_SIZE + 0x12345), Some(phys + PAGE_SIZE + 0x12345));
+        // 2MiBのページの一部だけは外せない
+        assert!(space.unmap(virt + PAGE_SIZE, PAGE_SIZE).is_err());
+        space.unmap(virt + PAGE_SIZE, huge).unwrap();
+        assert_eq!(space.translate(virt + PAGE_SIZE), None);
+        assert_eq!(space.translate(virt + huge + PAGE_SIZE), Some(phys + huge + PAGE_SIZE));
+        if has_1gib_pages() {
+            let giant = level_size(3);
+            space.map(giant, 0, giant, flags).unwrap();
+            assert_eq!(level(&space, giant + 0x1234_5678), Some(3));
+            assert_eq!(space.translate(giant + 0x1234_5678), Some(0x1234_5678));
+        }
+    }
+
+    #[test_case]
+    fn address_space_protects_and_guards_pages() {
+        let mut space = AddressSpace::new().unwrap();
+        let huge = level_size(2);
+        let virt = KERNEL_VIRTUAL_BASE + huge;
+        space.map(virt, 0x4000_0000, huge, PageFlags(PageFlags::WRITABLE)).unwrap();
+        // 2MiBのページの中の1ページだけ、書き込めなくする
+        space.protect(virt + PAGE_SIZE, PAGE_SIZE, PageFlags(PageFlags::NO_EXECUTE)).unwrap();
+        let (entry, level) = space.find_page(virt + PAGE_SIZE).unwrap();
+        assert_eq!(level, 1);
+        assert!(!entry.flags().contains(PageFlags::WRITABLE));
+        let (entry, level) = space.find_page(virt).unwrap();
+        assert_eq!(level, 1);
+        assert!(entry.flags().contains(PageFlags::WRITABLE));
+        assert_eq!(space.translate(virt + huge - 1), Some(0x4000_0000 + huge - 1));
+        // ガードページは写さないが、隣のページはそのまま
+        space.add_guard_page(virt + 2 * PAGE_SIZE).unwrap();
+        assert_eq!(space.translate(virt + 2 * PAGE_SIZE), None);
+        assert_eq!(space.translate(virt + 3 * PAGE_SIZE), Some(0x4000_0000 + 3 * PAGE_SIZE));
+        assert!(space.protect(virt + 2 * PAGE_SIZE, PAGE_SIZE, PageFlags(0)).is_err());
+        let flags = image_page_flags(sections(image_range().0).unwrap(), 0);
+        assert!(!flags.contains(PageFlags::WRITABLE) && flags.contains(PageFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn runtime_page_flags_follow_memory_attributes() {
+        let code = runtime_page_flags(MemoryAttribute(MemoryAttribute::RUNTIME.0 | MemoryAttribute::RO.0));
+        assert_eq!(code, PageFlags(0));
+        let data = runtime_page_flags(MemoryAttribute(MemoryAttribute::RUNTIME.0 | MemoryAttribute::XP.0));
+        assert_eq!(data, PageFlags(PageFlags::WRITABLE | PageFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn address_space_rejects_bad_mappings() {
+        let mut space = AddressSpace::new().unwrap();
+        let flags = PageFlags(PageFlags::WRITABLE);
+        assert!(space.map(0x1001, 0x1000, PAGE_SIZE, flags).is_err());
+        assert!(space.map(0x0000_8000_0000_0000, 0, PAGE_SIZE, flags).is_err());
+        assert!(is_canonical(KERNEL_VIRTUAL_BASE));
+    }
+
+    #[test_case]
+    fn current_page_tables_translate_code_addresses() {
+        // ファームウェアのページテーブルはidentity mapなので、関数のアドレスはそのまま変換される
+        let f = translate as fn(u64) -> Option<u64> as usize as u64;
+        assert_eq!(translate(f), Some(f));
+    }
+}
@@ -0,0 +1,39 @@
+// パニックしたときの処理
+// メッセージと場所、バックトレースを使える出力先すべてに出してから、QEMUを終了する
+// (QEMUの外ではisa-debug-exitがないので、そのまま止まる)
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use crate::backtrace::write_backtrace;
+use crate::logger::EmergencyConsole;
+use crate::power::exit_qemu;
+use crate::power::QemuExitCode;
+use crate::serial::com1;
+
+// パニックの報告中にもう一度パニックしたら、何もせずに終わる
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        // ここでは何が壊れているか分からないので、シリアルに直接書くだけにする
+        let _ = writeln!(com1(), "\nPANIC while panicking");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    let mut w = EmergencyConsole;
+    // cargo testのときは、どのテストが失敗したか分かるように
+    #[cfg(test)]
+    let _ = writeln!(w, "FAILED");
+    let _ = writeln!(w, "\nPANIC");
+    if let Some(location) = info.location() {
+        let _ = writeln!(w, "  at {}:{}:{}", location.file(), location.line(), location.column());
+    }
+    if let Some(message) = info.message() {
+        let _ = writeln!(w, "  {message}");
+    }
+    let _ = write_backtrace(&mut w);
+    exit_qemu(QemuExitCode::Failed)
+}
@@ -0,0 +1,112 @@
+// メモリに読み込まれたPEイメージ(このプログラム自身)のセクション
+// UEFIのローダーはヘッダもイメージの先頭にそのまま置くので、そこからセクションの表を読む
+// ページテーブルで、セクションごとに書き込みや実行を許すかを決めるのに使う
+
+use core::mem::offset_of;
+use core::mem::size_of;
+
+use crate::Result;
+
+// DOSヘッダの中の、PEヘッダの位置(e_lfanew)
+const PE_HEADER_OFFSET: usize = 0x3c;
+const PE_SIGNATURE: &[u8; 4] = b"PE\0\0";
+
+// PEの署名の後ろにあるCOFFファイルヘッダ
+#[repr(C)]
+struct CoffFileHeader {
+    machine: u16,
+    number_of_sections: u16,
+    time_date_stamp: u32,
+    pointer_to_symbol_table: u32,
+    number_of_symbols: u32,
+    size_of_optional_header: u16,
+    characteristics: u16,
+}
+
+const _: () = assert!(offset_of!(CoffFileHeader, number_of_sections) == 2);
+const _: () = assert!(offset_of!(CoffFileHeader, size_of_optional_header) == 16);
+const _: () = assert!(size_of::<CoffFileHeader>() == 20);
+
+#[repr(C)]
+pub struct PeSectionHeader {
+    name: [u8; 8],
+    // メモリ上での大きさと、イメージの先頭からのオフセット
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    size_of_raw_data: u32,
+    pointer_to_raw_data: u32,
+    pointer_to_relocations: u32,
+    pointer_to_linenumbers: u32,
+    number_of_relocations: u16,
+    number_of_linenumbers: u16,
+    characteristics: u32,
+}
+
+const _: () = assert!(offset_of!(PeSectionHeader, virtual_size) == 8);
+const _: () = assert!(offset_of!(PeSectionHeader, virtual_address) == 12);
+const _: () = assert!(offset_of!(PeSectionHeader, characteristics) == 36);
+const _: () = assert!(size_of::<PeSectionHeader>() == 40);
+
+impl PeSectionHeader {
+    const MEM_EXECUTE: u32 = 0x2000_0000;
+    const MEM_WRITE: u32 = 0x8000_0000;
+
+    // 8バイトに満たない名前は0で埋められている
+    #[allow(dead_code)]
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & Self::MEM_EXECUTE != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & Self::MEM_WRITE != 0
+    }
+
+    // メモリ上で占める範囲(イメージの先頭からのオフセット)
+    pub fn range(&self) -> (u64, u64) {
+        let start = self.virtual_address as u64;
+        (start, start + self.virtual_size as u64)
+    }
+}
+
+// image_baseに読み込まれたイメージのセクションの表
+pub fn sections(image_base: u64) -> Result<&'static [PeSectionHeader]> {
+    let base = image_base as *const u8;
+    unsafe {
+        if *base != b'M' || *base.add(1) != b'Z' {
+            return Err("Image does not start with MZ");
+        }
+        let pe = base.add((base.add(PE_HEADER_OFFSET) as *const u32).read_unaligned() as usize);
+        if *(pe as *const [u8; 4]) != *PE_SIGNATURE {
+            return Err("PE signature not found");
+        }
+        let coff = &*(pe.add(PE_SIGNATURE.len()) as *const CoffFileHeader);
+        let table = pe
+            .add(PE_SIGNATURE.len() + size_of::<CoffFileHeader>() + coff.size_of_optional_header as usize)
+            as *const PeSectionHeader;
+        Ok(core::slice::from_raw_parts(table, coff.number_of_sections as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backtrace::image_range;
+
+    #[test_case]
+    fn sections_of_this_image() {
+        let sections = sections(image_range().0).unwrap();
+        let text = sections.iter().find(|s| s.name() == ".text").unwrap();
+        assert!(text.is_executable() && !text.is_writable());
+        let data = sections.iter().find(|s| s.name() == ".data").unwrap();
+        assert!(data.is_writable() && !data.is_executable());
+        // 関数のコードは.textの中にある
+        let offset = image_range as fn() -> (u64, u64) as usize as u64 - image_range().0;
+        let (start, end) = text.range();
+        assert!((start..end).contains(&offset));
+    }
+}
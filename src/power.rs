@@ -0,0 +1,187 @@
+// 電源断、再起動、QEMUの終了
+// どれも戻ってこない。すべての方法が失敗したときはhltで止まる
+
+use core::ptr::write_volatile;
+
+use crate::acpi::Acpi;
+use crate::acpi::AcpiGenericAddress;
+use crate::acpi::Fadt;
+use crate::hlt;
+use crate::runtime::runtime_services;
+use crate::runtime::EfiResetType;
+use crate::x86::cli;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u32;
+use crate::x86::write_io_port_u8;
+use crate::EfiStatus;
+
+// launch_qemu.shで-device isa-debug-exit,iobase=0xf4,iosize=0x01として追加しているデバイス
+// 書き込んだ値をvとすると、QEMUは終了コード(v << 1) | 1で終了する
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+// QEMUの終了コードとして区別しやすい値(0を書き込むと終了コード1になり、QEMU自体のエラーと紛らわしい)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(dead_code)]
+pub enum QemuExitCode {
+    Success = 0x10, // 終了コード33
+    Failed = 0x11,  // 終了コード35
+}
+
+// PM1_CNTレジスタのビット
+const SLP_TYP_SHIFT: u32 = 10;
+const SLP_EN: u32 = 1 << 13;
+
+// QEMUのDSDTでは、\_S5のSLP_TYPa/SLP_TYPbはどちらも0
+const QEMU_S5_SLP_TYP: (u8, u8) = (0, 0);
+
+// キーボードコントローラ(i8042)
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 0x02;
+const KBC_COMMAND_RESET: u8 = 0xfe;
+
+// AML: \_S5_ = Package() { SLP_TYPa, SLP_TYPb, ... }
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+// DSDTから\_S5のSLP_TYPa, SLP_TYPbを探す
+// AMLをすべて解釈するのは大変なので、"_S5_"という名前の後ろにあるパッケージだけを読む
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let pos = aml.windows(4).position(|w| w == b"_S5_")?;
+    let mut p = &aml[pos + 4..];
+    if *p.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLengthは、先頭バイトの上位2ビットが後ろに続くバイト数を表す
+    let pkg_length_bytes = 1 + (*p.get(1)? >> 6) as usize;
+    p = p.get(1 + pkg_length_bytes + 1..)?; // PackageOp, PkgLength, NumElements
+    let mut read_integer = || -> Option<u8> {
+        let (value, len) = match *p.first()? {
+            AML_ZERO_OP => (0, 1),
+            AML_ONE_OP => (1, 1),
+            AML_BYTE_PREFIX => (*p.get(1)?, 2),
+            _ => return None,
+        };
+        p = &p[len..];
+        Some(value)
+    };
+    let slp_typ_a = read_integer()?;
+    let slp_typ_b = read_integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+// FADTなどにあるレジスタに書き込む
+fn write_register(reg: &AcpiGenericAddress, value: u32) {
+    let address = reg.address;
+    match reg.address_space_id {
+        AcpiGenericAddress::SPACE_SYSTEM_IO => match reg.register_bit_width {
+            8 => write_io_port_u8(address as u16, value as u8),
+            32 => write_io_port_u32(address as u16, value),
+            _ => write_io_port_u16(address as u16, value as u16),
+        },
+        AcpiGenericAddress::SPACE_SYSTEM_MEMORY => unsafe {
+            match reg.register_bit_width {
+                8 => write_volatile(address as *mut u8, value as u8),
+                32 => write_volatile(address as *mut u32, value),
+                _ => write_volatile(address as *mut u16, value as u16),
+            }
+        },
+        _ => {}
+    }
+}
+
+// ACPIのS5(ソフトオフ)に入る
+fn acpi_shutdown(acpi: &Acpi) {
+    let Some(fadt) = acpi.find::<Fadt>() else {
+        return;
+    };
+    let (slp_typ_a, slp_typ_b) = acpi
+        .dsdt()
+        .and_then(|dsdt| parse_s5(dsdt.data()))
+        .unwrap_or(QEMU_S5_SLP_TYP);
+    if let Some(pm1a) = fadt.pm1a_cnt_blk() {
+        write_register(&pm1a, ((slp_typ_a as u32) << SLP_TYP_SHIFT) | SLP_EN);
+    }
+    if let Some(pm1b) = fadt.pm1b_cnt_blk() {
+        write_register(&pm1b, ((slp_typ_b as u32) << SLP_TYP_SHIFT) | SLP_EN);
+    }
+}
+
+// FADTのリセットレジスタでリセットする
+fn acpi_reset(acpi: &Acpi) {
+    if let Some((reg, value)) = acpi.find::<Fadt>().and_then(|fadt| fadt.reset_register()) {
+        write_register(&reg, value as u32);
+    }
+}
+
+// キーボードコントローラにCPUのリセットを頼む
+fn keyboard_controller_reset() {
+    for _ in 0..0x10000 {
+        if read_io_port_u8(KBC_STATUS_PORT) & KBC_STATUS_INPUT_FULL == 0 {
+            break;
+        }
+    }
+    write_io_port_u8(KBC_COMMAND_PORT, KBC_COMMAND_RESET);
+}
+
+fn halt_forever() -> ! {
+    cli();
+    loop {
+        hlt();
+    }
+}
+
+// 電源を切る
+// ACPIで切れなければ、Runtime ServicesのResetSystemを使う
+pub fn shutdown(acpi: Option<&Acpi>) -> ! {
+    if let Some(acpi) = acpi {
+        acpi_shutdown(acpi);
+    }
+    if let Some(rt) = runtime_services() {
+        rt.reset_system(EfiResetType::Shutdown, EfiStatus::SUCCESS);
+    }
+    halt_forever()
+}
+
+// 再起動する
+// FADTのリセットレジスタ、キーボードコントローラ、Runtime ServicesのResetSystemの順に試す
+pub fn reboot(acpi: Option<&Acpi>) -> ! {
+    if let Some(acpi) = acpi {
+        acpi_reset(acpi);
+    }
+    keyboard_controller_reset();
+    if let Some(rt) = runtime_services() {
+        rt.reset_system(EfiResetType::Cold, EfiStatus::SUCCESS);
+    }
+    halt_forever()
+}
+
+// isa-debug-exitでQEMUを終了する。QEMUの外で動いているときは、そのまま止まる
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    exit_qemu_with(code as u8)
+}
+
+// iosizeが1なので、書き込めるのは1バイトだけ
+pub fn exit_qemu_with(value: u8) -> ! {
+    write_io_port_u8(ISA_DEBUG_EXIT_PORT, value);
+    halt_forever()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn parse_s5_reads_slp_typ() {
+        // Name(\_S5, Package(0x04) { 0x05, Zero, Zero, Zero })
+        let aml = [
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(parse_s5(&aml), Some((5, 0)));
+        assert_eq!(parse_s5(b"no sleep states"), None);
+    }
+}
@@ -0,0 +1,226 @@
+// UEFIのRuntime Services
+// 時計(RTC)の読み出し、不揮発な変数の読み書き、再起動や電源断ができる
+// Boot Servicesと違い、ExitBootServicesの後も使い続けられる
+// (wasabiは物理アドレスと同じ仮想アドレスでメモリを使うので、SetVirtualAddressMapの後もそのまま呼べる)
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::offset_of;
+use core::ptr::null;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use crate::memory_map::MemoryAttribute;
+use crate::to_ucs2;
+use crate::EfiGuid;
+use crate::EfiMemoryDescriptor;
+use crate::EfiResult;
+use crate::EfiStatus;
+use crate::EfiTime;
+use crate::EfiVoid;
+use crate::MemoryMapHolder;
+
+// UEFIの仕様で決められている変数(BootOrderなど)のベンダーGUID
+pub const EFI_GLOBAL_VARIABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0x8be4df61,
+    data1: 0x93ca,
+    data2: 0x11d2,
+    data3: [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c],
+};
+
+// wasabiが自分の設定を保存するときに使うベンダーGUID
+pub const WASABI_VARIABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0x6a5e3b1c,
+    data1: 0x8f2d,
+    data2: 0x4c61,
+    data3: [0x9e, 0x47, 0x2b, 0xd0, 0x5a, 0x13, 0xc8, 0x7f],
+};
+
+// 変数の属性
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[repr(transparent)]
+pub struct EfiVariableAttributes(pub u32);
+
+impl EfiVariableAttributes {
+    // 再起動しても消えない
+    pub const NON_VOLATILE: u32 = 0x1;
+    pub const BOOTSERVICE_ACCESS: u32 = 0x2;
+    pub const RUNTIME_ACCESS: u32 = 0x4;
+}
+
+// ResetSystemの種類
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[repr(u32)]
+#[allow(dead_code)]
+pub enum EfiResetType {
+    // 電源を入れ直したのと同じ状態にする
+    Cold = 0,
+    // CPUなどだけを初期化する
+    Warm = 1,
+    // 電源を切る
+    Shutdown = 2,
+}
+
+#[repr(C)]
+pub struct EfiRuntimeServicesTable {
+    _reserved0: [u64; 3], // ヘッダ
+    get_time: extern "win64" fn(time: *mut EfiTime, capabilities: *mut EfiVoid) -> EfiStatus,
+    _reserved1: [u64; 3], // SetTime, GetWakeupTime, SetWakeupTime
+    set_virtual_address_map: extern "win64" fn(
+        memory_map_size: usize,
+        descriptor_size: usize,
+        descriptor_version: u32,
+        virtual_map: *const EfiMemoryDescriptor,
+    ) -> EfiStatus,
+    _reserved2: [u64; 1], // ConvertPointer
+    get_variable: extern "win64" fn(
+        variable_name: *const u16,
+        vendor_guid: *const EfiGuid,
+        attributes: *mut u32,
+        data_size: *mut usize,
+        data: *mut EfiVoid,
+    ) -> EfiStatus,
+    _reserved3: [u64; 1], // GetNextVariableName
+    set_variable: extern "win64" fn(
+        variable_name: *const u16,
+        vendor_guid: *const EfiGuid,
+        attributes: u32,
+        data_size: usize,
+        data: *const EfiVoid,
+    ) -> EfiStatus,
+    _reserved4: [u64; 1], // GetNextHighMonotonicCount
+    reset_system: extern "win64" fn(
+        reset_type: EfiResetType,
+        reset_status: EfiStatus,
+        data_size: usize,
+        reset_data: *const EfiVoid,
+    ) -> !,
+}
+
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, get_time) == 24);
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, set_virtual_address_map) == 56);
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, get_variable) == 72);
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, set_variable) == 88);
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, reset_system) == 104);
+
+impl EfiRuntimeServicesTable {
+    // 今の日時をRTCから読む
+    pub fn get_time(&self) -> EfiResult<EfiTime> {
+        let mut time = EfiTime::default();
+        (self.get_time)(&mut time, null_mut()).into_result()?;
+        Ok(time)
+    }
+
+    // 変数の値をbufに読み込み、読み込んだバイト数と属性を返す
+    pub fn get_variable(
+        &self,
+        name: &str,
+        vendor_guid: &EfiGuid,
+        buf: &mut [u8],
+    ) -> EfiResult<(usize, EfiVariableAttributes)> {
+        let name = to_ucs2(name);
+        let mut attributes = 0;
+        let mut size = buf.len();
+        (self.get_variable)(
+            name.as_ptr(),
+            vendor_guid,
+            &mut attributes,
+            &mut size,
+            buf.as_mut_ptr(),
+        )
+        .into_result()?;
+        Ok((size, EfiVariableAttributes(attributes)))
+    }
+
+    // 変数の値を大きさを気にせずに読み込む
+    pub fn get_variable_vec(&self, name: &str, vendor_guid: &EfiGuid) -> EfiResult<Vec<u8>> {
+        let name = to_ucs2(name);
+        let mut data = Vec::new();
+        loop {
+            let mut size = data.len();
+            match (self.get_variable)(
+                name.as_ptr(),
+                vendor_guid,
+                null_mut(),
+                &mut size,
+                data.as_mut_ptr(),
+            )
+            .into_result()
+            {
+                Ok(()) => {
+                    data.truncate(size);
+                    return Ok(data);
+                }
+                Err(EfiStatus::BUFFER_TOO_SMALL) => data = vec![0; size],
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // 変数を書き込む。dataが空なら変数を削除する
+    pub fn set_variable(
+        &self,
+        name: &str,
+        vendor_guid: &EfiGuid,
+        attributes: EfiVariableAttributes,
+        data: &[u8],
+    ) -> EfiResult<()> {
+        let name = to_ucs2(name);
+        (self.set_variable)(
+            name.as_ptr(),
+            vendor_guid,
+            attributes.0,
+            data.len(),
+            data.as_ptr(),
+        )
+        .into_result()
+    }
+
+    // 再起動または電源断をする。この関数からは戻ってこない
+    pub fn reset_system(&self, reset_type: EfiResetType, status: EfiStatus) -> ! {
+        (self.reset_system)(reset_type, status, 0, null())
+    }
+
+    // Runtime Servicesを、memory_mapのvirtual_startに書かれた仮想アドレスで呼べるようにする
+    // ExitBootServicesの後に一度だけ呼べる
+    pub fn set_virtual_address_map(&self, memory_map: &MemoryMapHolder) -> EfiResult<()> {
+        (self.set_virtual_address_map)(
+            memory_map.memory_map_size,
+            memory_map.descriptor_size,
+            memory_map.descriptor_version,
+            memory_map.memory_map_buffer as *const EfiMemoryDescriptor,
+        )
+        .into_result()
+    }
+}
+
+impl MemoryMapHolder {
+    // Runtime Servicesが使う領域の仮想アドレスを、物理アドレスと同じにする
+    pub fn identity_map_runtime_regions(&mut self) {
+        if !self.is_valid() {
+            return;
+        }
+        let mut ofs = 0;
+        while ofs + self.descriptor_size <= self.memory_map_size {
+            let e = unsafe {
+                &mut *(self.memory_map_buffer.add(ofs) as *mut EfiMemoryDescriptor)
+            };
+            if e.attribute().contains(MemoryAttribute::RUNTIME) {
+                e.virtual_start = e.physical_start;
+            }
+            ofs += self.descriptor_size;
+        }
+    }
+}
+
+static RUNTIME_SERVICES: AtomicPtr<EfiRuntimeServicesTable> = AtomicPtr::new(null_mut());
+
+// efi_mainで一度だけ呼ぶ。以降はruntime_services()でどこからでも使える
+pub fn init(runtime_services: *const EfiRuntimeServicesTable) {
+    RUNTIME_SERVICES.store(runtime_services as *mut EfiRuntimeServicesTable, Ordering::SeqCst);
+}
+
+pub fn runtime_services() -> Option<&'static EfiRuntimeServicesTable> {
+    unsafe { RUNTIME_SERVICES.load(Ordering::SeqCst).as_ref() }
+}
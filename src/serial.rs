@@ -0,0 +1,103 @@
+// 16550互換のUART(シリアルポート)
+// QEMUでは-serial stdioで端末につながるので、画面がなくてもログを読んだり入力したりできる
+// 受信はポーリングで行う(割り込みはまだ使えないため)
+
+use core::fmt;
+
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+
+pub const COM1_BASE: u16 = 0x3f8;
+
+// UARTの入力クロック。ボーレートはこれを分周して作る
+const UART_CLOCK: u32 = 115200;
+
+// レジスタのベースからのオフセット
+const REG_DATA: u16 = 0; // 送受信(DLAB=1のときは分周比の下位)
+const REG_INTERRUPT_ENABLE: u16 = 1; // 割り込み許可(DLAB=1のときは分周比の上位)
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_SCRATCH: u16 = 7;
+
+const LINE_CONTROL_8N1: u8 = 0x03; // 8ビット、パリティなし、ストップビット1
+const LINE_CONTROL_DLAB: u8 = 0x80; // 分周比を設定するときに立てる
+const FIFO_ENABLE_AND_CLEAR: u8 = 0xc7; // FIFOを有効にして送受信ともに空にする。受信は14バイトで割り込み
+const MODEM_CONTROL_DTR_RTS_OUT2: u8 = 0x0b;
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort { base }
+    }
+
+    // ボーレートと、8N1、FIFOを設定する
+    // ポートが存在しない(スクラッチレジスタが読み書きできない)ときはエラーにする
+    pub fn init(&self, baud: u32) -> Result<(), &'static str> {
+        write_io_port_u8(self.base + REG_SCRATCH, 0x5a);
+        if read_io_port_u8(self.base + REG_SCRATCH) != 0x5a {
+            return Err("Serial port not found");
+        }
+        let divisor = (UART_CLOCK / baud.clamp(1, UART_CLOCK)) as u16;
+        write_io_port_u8(self.base + REG_INTERRUPT_ENABLE, 0x00);
+        write_io_port_u8(self.base + REG_LINE_CONTROL, LINE_CONTROL_DLAB);
+        write_io_port_u8(self.base + REG_DATA, divisor as u8);
+        write_io_port_u8(self.base + REG_INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        write_io_port_u8(self.base + REG_LINE_CONTROL, LINE_CONTROL_8N1);
+        write_io_port_u8(self.base + REG_FIFO_CONTROL, FIFO_ENABLE_AND_CLEAR);
+        write_io_port_u8(self.base + REG_MODEM_CONTROL, MODEM_CONTROL_DTR_RTS_OUT2);
+        Ok(())
+    }
+
+    fn line_status(&self) -> u8 {
+        read_io_port_u8(self.base + REG_LINE_STATUS)
+    }
+
+    // 送信バッファが空くまで待ってから1バイト送る
+    pub fn send_byte(&self, byte: u8) {
+        while self.line_status() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        write_io_port_u8(self.base + REG_DATA, byte);
+    }
+
+    // 受信したバイトがあれば返す。なければすぐにNoneを返す
+    pub fn try_read_byte(&self) -> Option<u8> {
+        (self.line_status() & LINE_STATUS_DATA_READY != 0)
+            .then(|| read_io_port_u8(self.base + REG_DATA))
+    }
+
+    // 1バイト受信するまで待つ
+    pub fn read_byte(&self) -> u8 {
+        loop {
+            if let Some(b) = self.try_read_byte() {
+                return b;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// 端末で正しく改行されるように、\nは\r\nにして送る
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.send_byte(b'\r');
+            }
+            self.send_byte(b);
+        }
+        Ok(())
+    }
+}
+
+pub fn com1() -> SerialPort {
+    SerialPort::new(COM1_BASE)
+}
@@ -0,0 +1,112 @@
+// バックトレースを関数名で表示するためのシンボル表
+// scripts/gen_symbols.shがPDBから作り、launch_qemu.shがESPのEFI/BOOT/WASABI.SYMに置く
+// 1行に1つ「RVA 大きさ 関数名」(RVAと大きさは16進)が、RVAの小さい順に並んでいる
+// Boot Servicesが使えるうちに読み込み、その後は(パニックの中からも)ロックなしで引けるようにしておく
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+use crate::backtrace::image_offset;
+use crate::file::open_boot_volume;
+use crate::EfiBootServiceTable;
+use crate::EfiHandle;
+use crate::Result;
+
+pub const SYMBOL_FILE_PATH: &str = "EFI/BOOT/WASABI.SYM";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    // イメージの先頭からのオフセット
+    pub rva: u64,
+    pub size: u64,
+    pub name: &'a str,
+}
+
+pub struct SymbolTable<'a> {
+    // RVAの小さい順
+    symbols: Vec<Symbol<'a>>,
+}
+
+impl<'a> SymbolTable<'a> {
+    // 読めない行は飛ばす
+    pub fn parse(text: &'a str) -> SymbolTable<'a> {
+        let mut symbols: Vec<Symbol> = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, ' ');
+                let rva = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let size = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let name = fields.next()?;
+                Some(Symbol { rva, size, name })
+            })
+            .collect();
+        symbols.sort_unstable_by_key(|s| s.rva);
+        SymbolTable { symbols }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    // rvaを含む関数と、その先頭からのオフセットを返す
+    pub fn find(&self, rva: u64) -> Option<(&Symbol<'a>, u64)> {
+        let i = self.symbols.partition_point(|s| s.rva <= rva).checked_sub(1)?;
+        let symbol = &self.symbols[i];
+        (rva - symbol.rva < symbol.size).then(|| (symbol, rva - symbol.rva))
+    }
+}
+
+// 一度読み込んだら解放しない
+static SYMBOLS: AtomicPtr<SymbolTable<'static>> = AtomicPtr::new(null_mut());
+
+// ESPからシンボル表を読み込んで、見つかった関数の数を返す
+pub fn load(boot_services: &EfiBootServiceTable, image_handle: EfiHandle) -> Result<usize> {
+    let data = open_boot_volume(boot_services, image_handle)
+        .and_then(|root| root.open(SYMBOL_FILE_PATH))
+        .and_then(|mut file| file.read_to_end())
+        .or(Err("Symbol file not found"))?;
+    let text = core::str::from_utf8(data.leak()).or(Err("Symbol file is not UTF-8"))?;
+    let table = Box::leak(Box::new(SymbolTable::parse(text)));
+    SYMBOLS.store(table, Ordering::SeqCst);
+    Ok(table.len())
+}
+
+pub fn symbols() -> Option<&'static SymbolTable<'static>> {
+    unsafe { SYMBOLS.load(Ordering::SeqCst).as_ref() }
+}
+
+// 実行中のアドレスから、関数名と関数の先頭からのオフセットを引く
+pub fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    let (symbol, offset) = symbols()?.find(image_offset(address)?)?;
+    Some((symbol.name, offset))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn symbol_table_finds_containing_function() {
+        let table = SymbolTable::parse(
+            "00001000 10 wasabi::a\n\
+             00001020 8 core::cmp::Ordering (*)(ref$<u32>)\n\
+             broken line\n\
+             00001010 4 wasabi::b\n",
+        );
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.find(0x1000).map(|(s, off)| (s.name, off)), Some(("wasabi::a", 0)));
+        assert_eq!(table.find(0x100f).map(|(s, off)| (s.name, off)), Some(("wasabi::a", 0xf)));
+        assert_eq!(table.find(0x1012).map(|(s, off)| (s.name, off)), Some(("wasabi::b", 2)));
+        assert_eq!(
+            table.find(0x1024).map(|(s, off)| (s.name, off)),
+            Some(("core::cmp::Ordering (*)(ref$<u32>)", 4))
+        );
+        // 関数と関数の間や、表の範囲の外
+        assert!(table.find(0x1016).is_none());
+        assert!(table.find(0xfff).is_none());
+        assert!(table.find(0x1028).is_none());
+    }
+}
@@ -0,0 +1,58 @@
+// cargo testで使うテストフレームワーク
+// #[test_case]を付けた関数を、QEMUで起動したwasabiの中で順に実行する
+// 結果はシリアル(COM1)に出力し、最後にisa-debug-exitで成功か失敗かをQEMUに伝える
+
+use core::any::type_name;
+use core::fmt::Write;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use crate::power::exit_qemu;
+use crate::power::QemuExitCode;
+use crate::serial::com1;
+use crate::EfiHandle;
+use crate::EfiSystemTable;
+
+static SYSTEM_TABLE: AtomicPtr<EfiSystemTable> = AtomicPtr::new(core::ptr::null_mut());
+static IMAGE_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+// テストを始める前に、efi_mainで一度だけ呼ぶ
+pub fn init(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+    IMAGE_HANDLE.store(image_handle, Ordering::SeqCst);
+    SYSTEM_TABLE.store(
+        efi_system_table as *const EfiSystemTable as *mut EfiSystemTable,
+        Ordering::SeqCst,
+    );
+}
+
+// テストの中からファームウェアの機能を使うときに使う
+pub fn system_table() -> &'static EfiSystemTable {
+    unsafe { SYSTEM_TABLE.load(Ordering::SeqCst).as_ref() }.expect("test_runner::init was not called")
+}
+
+pub fn image_handle() -> EfiHandle {
+    IMAGE_HANDLE.load(Ordering::SeqCst)
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let _ = write!(com1(), "{} ... ", type_name::<T>());
+        self();
+        let _ = writeln!(com1(), "ok");
+    }
+}
+
+// すべてのテストが戻ってきたら成功。失敗したテストはパニックし、panic.rsのハンドラがQEMUを終了する
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let _ = writeln!(com1(), "running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    let _ = writeln!(com1(), "test result: ok. {} passed", tests.len());
+    exit_qemu(QemuExitCode::Success);
+}
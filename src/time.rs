@@ -0,0 +1,70 @@
+// 起動してからの時間
+// TSC(Time Stamp Counter)で測る。TSCの周波数はACPI PMタイマーと比べて求める
+
+use core::fmt;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use crate::acpi::Acpi;
+use crate::acpi::Fadt;
+use crate::x86::rdtsc;
+use crate::x86::read_io_port_u32;
+
+// ACPI PMタイマーの周波数は仕様で決まっている
+const PM_TIMER_HZ: u64 = 3_579_545;
+// 周波数を測るときに待つ時間
+const CALIBRATION_MS: u64 = 10;
+
+static TSC_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+// 0ならまだ分からない
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+// efi_mainのできるだけ早いうちに呼ぶ
+pub fn init() {
+    TSC_AT_BOOT.store(rdtsc(), Ordering::SeqCst);
+}
+
+// PMタイマーがCALIBRATION_MSだけ進む間にTSCがいくつ進むかで、TSCの周波数を求める
+pub fn calibrate(acpi: &Acpi) -> Option<u64> {
+    let (port, bits) = acpi.find::<Fadt>()?.pm_timer()?;
+    let mask = if bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+    let wait_ticks = (PM_TIMER_HZ * CALIBRATION_MS / 1000) as u32;
+    let pm_start = read_io_port_u32(port) & mask;
+    let tsc_start = rdtsc();
+    // カウンタは一周して0に戻るので、差をマスクして比べる
+    while (read_io_port_u32(port).wrapping_sub(pm_start) & mask) < wait_ticks {
+        core::hint::spin_loop();
+    }
+    let khz = (rdtsc() - tsc_start) / CALIBRATION_MS;
+    TSC_KHZ.store(khz, Ordering::SeqCst);
+    Some(khz)
+}
+
+// 起動してからの時間。周波数が分からないうちはTSCの値をそのまま表示する
+#[derive(Debug, Clone, Copy)]
+pub struct Uptime {
+    ticks: u64,
+    khz: u64,
+}
+
+impl Uptime {
+    pub fn as_micros(&self) -> Option<u64> {
+        (self.khz != 0).then(|| self.ticks / (self.khz / 1000).max(1))
+    }
+}
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_micros() {
+            Some(us) => write!(f, "{:5}.{:06}", us / 1_000_000, us % 1_000_000),
+            None => write!(f, "tsc {:10}", self.ticks),
+        }
+    }
+}
+
+pub fn uptime() -> Uptime {
+    Uptime {
+        ticks: rdtsc().wrapping_sub(TSC_AT_BOOT.load(Ordering::SeqCst)),
+        khz: TSC_KHZ.load(Ordering::SeqCst),
+    }
+}
@@ -0,0 +1,254 @@
+// x86_64の特権命令のラッパー
+
+use core::arch::asm;
+
+// I/Oポートから読み書きする
+pub fn read_io_port_u8(port: u16) -> u8 {
+    let value: u8;
+    unsafe {
+        asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+pub fn read_io_port_u32(port: u16) -> u32 {
+    let value: u32;
+    unsafe {
+        asm!("in eax, dx", in("dx") port, out("eax") value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+pub fn write_io_port_u8(port: u16, value: u8) {
+    unsafe {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+pub fn write_io_port_u16(port: u16, value: u16) {
+    unsafe {
+        asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+pub fn write_io_port_u32(port: u16, value: u32) {
+    unsafe {
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+// 割り込みを禁止する
+pub fn cli() {
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+}
+
+// CPUが起動してからのクロック数(Time Stamp Counter)
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+// 今の関数のフレームポインタ
+#[inline(always)]
+pub fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+// 今のスタックポインタ
+#[inline(always)]
+pub fn read_rsp() -> u64 {
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    rsp
+}
+
+// 今使っているページテーブル(PML4)の物理アドレスと、フラグ
+pub fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3
+}
+
+// SGDT/SIDTが書き込む形式
+#[repr(C, packed)]
+#[derive(Default)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+// GDTの(先頭のアドレス, 大きさ)
+pub fn read_gdtr() -> (u64, u64) {
+    let mut gdtr = DescriptorTablePointer::default();
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags));
+    }
+    (gdtr.base, gdtr.limit as u64 + 1)
+}
+
+// IDTの(先頭のアドレス, 大きさ)
+pub fn read_idtr() -> (u64, u64) {
+    let mut idtr = DescriptorTablePointer::default();
+    unsafe {
+        asm!("sidt [{}]", in(reg) &mut idtr, options(nostack, preserves_flags));
+    }
+    (idtr.base, idtr.limit as u64 + 1)
+}
+
+// GDTを読み込んで、CSを含むセグメントレジスタを新しいセレクタにする
+// CSはMOVでは変えられないので、far returnで読み込み直す
+pub unsafe fn load_gdt(base: u64, size: u64, code_selector: u16, data_selector: u16) {
+    let gdtr = DescriptorTablePointer {
+        limit: (size - 1) as u16,
+        base,
+    };
+    asm!(
+        "lgdt [{gdtr}]",
+        "push {code}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "mov fs, {data:x}",
+        "mov gs, {data:x}",
+        "mov ss, {data:x}",
+        gdtr = in(reg) &gdtr,
+        code = in(reg) code_selector as u64,
+        data = in(reg) data_selector,
+        tmp = out(reg) _,
+        options(preserves_flags),
+    );
+}
+
+pub unsafe fn load_idt(base: u64, size: u64) {
+    let idtr = DescriptorTablePointer {
+        limit: (size - 1) as u16,
+        base,
+    };
+    asm!("lidt [{}]", in(reg) &idtr, options(nostack, preserves_flags));
+}
+
+// TSSを読み込む。GDTの中のTSSディスクリプタは使用中(busy)になる
+pub unsafe fn load_tss(selector: u16) {
+    asm!("ltr {:x}", in(reg) selector, options(nostack, preserves_flags));
+}
+
+// ページフォルトを起こしたアドレス
+pub fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
+
+pub fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    }
+    cr0
+}
+
+// カーネルも、書き込みを許していないページには書き込めなくする
+pub const CR0_WP: u64 = 1 << 16;
+
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+}
+
+// ページテーブルを切り替える。TLBはGLOBALのものを除いて消える
+// 今実行しているコードとスタックが、新しいページテーブルでも同じアドレスに写っていなければならない
+pub unsafe fn write_cr3(cr3: u64) {
+    asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+}
+
+// addressを含むページのTLBを消す
+pub fn invlpg(address: u64) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags));
+    }
+}
+
+// CPUIDの(EAX, EBX, ECX, EDX)
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let r = unsafe { core::arch::x86_64::__cpuid(leaf) };
+    (r.eax, r.ebx, r.ecx, r.edx)
+}
+
+// 1GiBのページが使えるか
+pub fn has_1gib_pages() -> bool {
+    cpuid(0x8000_0000).0 >= 0x8000_0001 && cpuid(0x8000_0001).3 & (1 << 26) != 0
+}
+
+// ページテーブルのNO_EXECUTEのビットが使えるか
+pub fn has_nx() -> bool {
+    cpuid(0x8000_0000).0 >= 0x8000_0001 && cpuid(0x8000_0001).3 & (1 << 20) != 0
+}
+
+// PAT(Page Attribute Table)が使えるか
+pub fn has_pat() -> bool {
+    cpuid(1).3 & (1 << 16) != 0
+}
+
+pub const MSR_IA32_PAT: u32 = 0x277;
+pub const MSR_IA32_EFER: u32 = 0xc000_0080;
+// ページテーブルのNO_EXECUTEのビットを有効にする
+pub const EFER_NXE: u64 = 1 << 11;
+
+pub fn rdmsr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+// MSRの意味によっては、メモリの見え方やCPUの動作が変わる
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+}
+
+// キャッシュの内容をメモリに書き戻してから捨てる
+pub fn wbinvd() {
+    unsafe {
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+}
+
+// スタックをtopに切り替えてから、f()を呼ぶ。元のスタックには戻らないので、fも戻ってはいけない
+// fは元のスタックの上にあるので、元のスタックは(fが読み出されるまでは)写したままにしておく
+pub unsafe fn switch_stack<F: FnOnce()>(top: u64, f: F) -> ! {
+    extern "sysv64" fn trampoline<F: FnOnce()>(f: *mut F) -> ! {
+        unsafe { f.read()() };
+        panic!("Returned to the top of a switched stack");
+    }
+    let mut f = f;
+    // RBPを0にして、バックトレースが新しいスタックの一番上で止まるようにする
+    asm!(
+        "mov rsp, {top}",
+        "xor ebp, ebp",
+        "call {trampoline}",
+        top = in(reg) top,
+        trampoline = in(reg) trampoline::<F> as usize,
+        in("rdi") &mut f as *mut F,
+        options(noreturn),
+    );
+}